@@ -1,25 +1,46 @@
+use crate::helix::{HelixClient, HelixError};
 use axum::http::{HeaderMap, HeaderValue};
 use const_format::formatcp;
 use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 lazy_static! {
     /// プロセス全体で使い回す HTTP クライアント。呼び出しごとに新規生成すると
     /// DNS+TLS ハンドシェイクを払い直すため、コネクションプール/keep-alive を
     /// 共有して再利用する。
-    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+    pub static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
 }
 
 const TWITCH_API_HOST: &str = "api.twitch.tv";
 const TWITCH_USERS_API_URL: &str = formatcp!("https://{}/helix/users", TWITCH_API_HOST);
 const TWITCH_BANS_API_URL: &str = formatcp!("https://{}/helix/moderation/bans", TWITCH_API_HOST);
+const TWITCH_BANNED_API_URL: &str =
+    formatcp!("https://{}/helix/moderation/banned", TWITCH_API_HOST);
 const TWITCH_CHATTERS_API_URL: &str = formatcp!("https://{}/helix/chat/chatters", TWITCH_API_HOST);
 const TWITCH_FOLLOWED_API_URL: &str =
     formatcp!("https://{}/helix/channels/followed", TWITCH_API_HOST);
+const TWITCH_CHAT_MESSAGES_API_URL: &str =
+    formatcp!("https://{}/helix/chat/messages", TWITCH_API_HOST);
+const TWITCH_CHAT_SETTINGS_API_URL: &str =
+    formatcp!("https://{}/helix/chat/settings", TWITCH_API_HOST);
+const TWITCH_SHIELD_MODE_API_URL: &str =
+    formatcp!("https://{}/helix/moderation/shield_mode", TWITCH_API_HOST);
+const TWITCH_STREAMS_API_URL: &str = formatcp!("https://{}/helix/streams", TWITCH_API_HOST);
 const TWITCH_SUB_EVENT_API_URL: &str =
     formatcp!("https://{}/helix/eventsub/subscriptions", TWITCH_API_HOST);
+/// Get Users の `login` を 1 リクエストに載せられる上限。
+const USERS_PER_REQUEST: usize = 100;
+/// Get Banned Users の `first` の上限。
+const BANNED_PER_PAGE: &str = "100";
+/// Get Chatters の `first` の上限。
+const CHATTERS_PER_PAGE: &str = "1000";
+/// Get Followed Channels の `first` の上限。
+const FOLLOWED_PER_PAGE: usize = 100;
 const TWITCH_ID_HOST: &str = "id.twitch.tv";
 const TWITCH_OAUTH2_TOKEN_URL: &str = formatcp!("https://{}/oauth2/token", TWITCH_ID_HOST);
+const TWITCH_OAUTH2_DEVICE_URL: &str = formatcp!("https://{}/oauth2/device", TWITCH_ID_HOST);
+const TWITCH_OAUTH2_VALIDATE_URL: &str = formatcp!("https://{}/oauth2/validate", TWITCH_ID_HOST);
 pub const TWITCH_OAUTH2_AUTHZ_URL: &str = formatcp!("https://{}/oauth2/authorize", TWITCH_ID_HOST);
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,22 +61,30 @@ pub struct UserData {
     pub created_at: String,
 }
 
-pub async fn get_user(
-    username: &str,
-    access_token: &str,
-    client_id: &str,
-) -> Result<User, reqwest::Error> {
-    let headers = auth_headers(access_token, client_id);
-    let res: User = HTTP_CLIENT
-        .get(TWITCH_USERS_API_URL)
-        .headers(headers)
-        .query(&[("login", username)])
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
-    Ok(res)
+pub async fn get_user(helix: &mut HelixClient, username: &str) -> Result<User, HelixError> {
+    helix
+        .send_json(|c| c.get(TWITCH_USERS_API_URL).query(&[("login", username)]))
+        .await
+}
+
+/// `logins` を 100 件ずつまとめて引く。存在しない login は結果に含まれない。
+pub async fn get_users(
+    helix: &mut HelixClient,
+    logins: &[String],
+) -> Result<Vec<UserData>, HelixError> {
+    let mut users = Vec::with_capacity(logins.len());
+    for chunk in logins.chunks(USERS_PER_REQUEST) {
+        let query = login_query(chunk);
+        let res: User = helix
+            .send_json(|c| c.get(TWITCH_USERS_API_URL).query(&query))
+            .await?;
+        users.extend(res.data);
+    }
+    Ok(users)
+}
+
+fn login_query(logins: &[String]) -> Vec<(&str, &str)> {
+    logins.iter().map(|l| ("login", l.as_str())).collect()
 }
 
 #[derive(Serialize, Deserialize)]
@@ -87,6 +116,110 @@ pub async fn get_tokens_by_refresh(
     Ok((res.access_token, res.refresh_token))
 }
 
+/// Device Code Grant の開始応答。`verification_uri` には `device-code` が埋め込み済み。
+#[derive(Deserialize, Debug)]
+pub struct DeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+pub async fn get_device_code(client_id: &str, scopes: &str) -> Result<DeviceCode, reqwest::Error> {
+    HTTP_CLIENT
+        .post(TWITCH_OAUTH2_DEVICE_URL)
+        .timeout(std::time::Duration::from_secs(30))
+        .form(&[("client_id", client_id), ("scopes", scopes)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+/// Device Code Grant のトークン要求 1 回分の結果。
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeviceTokenPoll {
+    Granted {
+        access_token: String,
+        refresh_token: String,
+    },
+    /// ユーザーがまだコードを入力していない。
+    Pending,
+    /// 問い合わせが速すぎる。間隔を広げて続ける。
+    SlowDown,
+    /// 拒否・期限切れなど、続けても成功しない。
+    Failed(String),
+}
+
+impl DeviceTokenPoll {
+    fn from_error_message(message: String) -> Self {
+        match message.as_str() {
+            "authorization_pending" => Self::Pending,
+            "slow_down" => Self::SlowDown,
+            _ => Self::Failed(message),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OAuthError {
+    message: String,
+}
+
+pub async fn poll_device_token(
+    client_id: &str,
+    scopes: &str,
+    device_code: &str,
+) -> Result<DeviceTokenPoll, reqwest::Error> {
+    let res = HTTP_CLIENT
+        .post(TWITCH_OAUTH2_TOKEN_URL)
+        .timeout(std::time::Duration::from_secs(30))
+        .form(&[
+            ("client_id", client_id),
+            ("scopes", scopes),
+            ("device_code", device_code),
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+        ])
+        .send()
+        .await?;
+    if res.status() == reqwest::StatusCode::BAD_REQUEST {
+        let err: OAuthError = res.json().await?;
+        return Ok(DeviceTokenPoll::from_error_message(err.message));
+    }
+    let token: AccessToken = res.error_for_status()?.json().await?;
+    Ok(DeviceTokenPoll::Granted {
+        access_token: token.access_token,
+        refresh_token: token.refresh_token,
+    })
+}
+
+/// `/oauth2/validate` の応答。ユーザートークンでは `login` / `user_id` が入る。
+#[derive(Deserialize, Debug)]
+pub struct ValidatedToken {
+    pub client_id: String,
+    pub login: Option<String>,
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// 残り有効秒数。期限の無いトークンでは 0。
+    pub expires_in: u64,
+}
+
+/// トークンの有効性とスコープを確かめる。無効なら 401 が返る。
+pub async fn validate_token(access_token: &str) -> Result<ValidatedToken, reqwest::Error> {
+    HTTP_CLIENT
+        .get(TWITCH_OAUTH2_VALIDATE_URL)
+        .timeout(std::time::Duration::from_secs(30))
+        .header("Authorization", format!("OAuth {access_token}"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
 #[derive(Serialize, Deserialize)]
 struct AccessToken {
     access_token: String,
@@ -129,38 +262,54 @@ struct BanData<'a> {
     reason: &'a str,
 }
 
+/// `broadcaster_id` のチャンネルで `banned_id` を BAN する。`moderator_id` はトークンの持ち主。
 pub async fn ban_user(
-    operator_id: &str,
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+    moderator_id: &str,
     banned_id: &str,
-    access_token: &str,
-    client_id: &str,
-) -> Result<String, reqwest::Error> {
-    let headers = auth_headers(access_token, client_id);
+    reason: &str,
+) -> Result<String, HelixError> {
     let ban = Ban {
         data: BanData {
             user_id: banned_id,
-            reason: "bot",
+            reason,
         },
     };
-    let res = HTTP_CLIENT
-        .post(TWITCH_BANS_API_URL)
-        .headers(headers)
-        .query(&[
-            ("broadcaster_id", operator_id),
-            ("moderator_id", operator_id),
-        ])
-        .json(&ban)
-        .send()
+    let res = helix
+        .send(|c| {
+            c.post(TWITCH_BANS_API_URL)
+                .query(&[
+                    ("broadcaster_id", broadcaster_id),
+                    ("moderator_id", moderator_id),
+                ])
+                .json(&ban)
+        })
         .await?
-        .error_for_status()?
         .text()
         .await?;
     Ok(res)
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct Chatters {
-    pub data: Vec<Chatter>,
+#[derive(Deserialize)]
+pub struct BannedUser {
+    pub user_id: String,
+}
+
+/// `broadcaster_id` のチャンネルで BAN 済みのユーザー（期限付きを含む）。
+pub async fn get_banned_users(
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+) -> Result<Vec<BannedUser>, HelixError> {
+    get_all_pages(
+        helix,
+        TWITCH_BANNED_API_URL,
+        &[
+            ("broadcaster_id", broadcaster_id),
+            ("first", BANNED_PER_PAGE),
+        ],
+    )
+    .await
 }
 
 #[derive(Serialize, Deserialize)]
@@ -171,35 +320,100 @@ pub struct Chatter {
 }
 
 pub async fn get_chatters(
+    helix: &mut HelixClient,
     broadcaster_id: &str,
     operator_id: &str,
-    access_token: &str,
-    client_id: &str,
-) -> Result<Chatters, reqwest::Error> {
-    let headers = auth_headers(access_token, client_id);
-    let res: Chatters = HTTP_CLIENT
-        .get(TWITCH_CHATTERS_API_URL)
-        .headers(headers)
-        .query(&[
+) -> Result<Vec<Chatter>, HelixError> {
+    get_all_pages(
+        helix,
+        TWITCH_CHATTERS_API_URL,
+        &[
             ("broadcaster_id", broadcaster_id),
             ("moderator_id", operator_id),
-        ])
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
-    Ok(res)
+            ("first", CHATTERS_PER_PAGE),
+        ],
+    )
+    .await
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default)]
 pub struct Pagination {
     pub cursor: Option<String>,
 }
-#[derive(Deserialize, Serialize)]
-pub struct Followeds {
-    pub data: Vec<Followed>,
-    pub pagination: Pagination,
+
+impl Pagination {
+    /// 次のページのカーソル。最終ページでは省略されるか空文字で返る。
+    pub fn next(&self) -> Option<&str> {
+        self.cursor.as_deref().filter(|c| !c.is_empty())
+    }
+}
+
+/// `data` と `pagination` を持つ一覧系エンドポイントの 1 ページ。`extra` にはそれ以外の
+/// フィールド（合計値など）が入る。
+#[derive(Deserialize)]
+struct Page<T, E = NoExtra> {
+    data: Vec<T>,
+    #[serde(default)]
+    pagination: Pagination,
+    #[serde(flatten)]
+    extra: E,
+}
+
+/// `data` と `pagination` 以外を読まない。
+#[derive(Deserialize)]
+struct NoExtra {}
+
+/// カーソルを辿って全ページの `data` を集める。`query` には `after` 以外を渡す。
+async fn get_all_pages<T: DeserializeOwned>(
+    helix: &mut HelixClient,
+    url: &str,
+    query: &[(&str, &str)],
+) -> Result<Vec<T>, HelixError> {
+    get_pages(helix, url, query, None).await
+}
+
+/// `get_all_pages` と同じだが、`limit` 件集まったらそれ以降のページは取らない。
+async fn get_pages<T: DeserializeOwned>(
+    helix: &mut HelixClient,
+    url: &str,
+    query: &[(&str, &str)],
+    limit: Option<usize>,
+) -> Result<Vec<T>, HelixError> {
+    let (items, _) = get_pages_with::<T, NoExtra>(helix, url, query, limit).await?;
+    Ok(items)
+}
+
+/// `get_pages` と同じだが、最初のページの `data` と `pagination` 以外も `E` として返す。
+async fn get_pages_with<T: DeserializeOwned, E: DeserializeOwned>(
+    helix: &mut HelixClient,
+    url: &str,
+    query: &[(&str, &str)],
+    limit: Option<usize>,
+) -> Result<(Vec<T>, Option<E>), HelixError> {
+    let mut items = Vec::new();
+    let mut extra = None;
+    let mut after: Option<String> = None;
+    loop {
+        let page: Page<T, E> = helix
+            .send_json(|c| {
+                let req = c.get(url).query(query);
+                match &after {
+                    Some(cursor) => req.query(&[("after", cursor)]),
+                    None => req,
+                }
+            })
+            .await?;
+        items.extend(page.data);
+        extra.get_or_insert(page.extra);
+        if let Some(limit) = limit.filter(|l| items.len() >= *l) {
+            items.truncate(limit);
+            return Ok((items, extra));
+        }
+        match page.pagination.next() {
+            Some(cursor) => after = Some(cursor.to_string()),
+            None => return Ok((items, extra)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -207,92 +421,318 @@ pub struct Followed {
     pub broadcaster_id: String,
     pub broadcaster_login: String,
     pub broadcaster_name: String,
+    /// RFC 3339（UTC）。
+    pub followed_at: String,
 }
 
+/// `user_id` がフォローしているチャンネル（新しい順）。`limit` 件で打ち切る。
 pub async fn get_followed(
+    helix: &mut HelixClient,
     user_id: &str,
-    first: &i64,
-    after: &str,
-    access_token: &str,
-    client_id: &str,
-) -> Result<Followeds, reqwest::Error> {
-    let headers = auth_headers(access_token, client_id);
-    let first_s = first.to_string();
-    let queries = match after.is_empty() {
-        true => vec![("user_id", user_id), ("first", first_s.as_str())],
-        false => vec![
-            ("user_id", user_id),
-            ("first", first_s.as_str()),
-            ("after", after),
-        ],
-    };
-    let res: Followeds = HTTP_CLIENT
-        .get(TWITCH_FOLLOWED_API_URL)
-        .headers(headers)
-        .query(&queries)
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
+    limit: Option<usize>,
+) -> Result<Vec<Followed>, HelixError> {
+    let first = limit
+        .map_or(FOLLOWED_PER_PAGE, |l| l.min(FOLLOWED_PER_PAGE))
+        .to_string();
+    get_pages(
+        helix,
+        TWITCH_FOLLOWED_API_URL,
+        &[("user_id", user_id), ("first", first.as_str())],
+        limit,
+    )
+    .await
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChatSettings {
+    pub data: Vec<ChatSettingsData>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatSettingsData {
+    pub follower_mode: bool,
+}
+
+pub async fn get_chat_settings(
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+    operator_id: &str,
+) -> Result<ChatSettings, HelixError> {
+    helix
+        .send_json(|c| {
+            c.get(TWITCH_CHAT_SETTINGS_API_URL).query(&[
+                ("broadcaster_id", broadcaster_id),
+                ("moderator_id", operator_id),
+            ])
+        })
+        .await
+}
+
+#[derive(Serialize)]
+struct FollowerModePatch {
+    follower_mode: bool,
+}
+
+pub async fn update_follower_mode(
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+    operator_id: &str,
+    follower_mode: bool,
+) -> Result<(), HelixError> {
+    helix
+        .send(|c| {
+            c.patch(TWITCH_CHAT_SETTINGS_API_URL)
+                .query(&[
+                    ("broadcaster_id", broadcaster_id),
+                    ("moderator_id", operator_id),
+                ])
+                .json(&FollowerModePatch { follower_mode })
+        })
         .await?;
-    Ok(res)
+    Ok(())
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ShieldModeStatus {
+    pub data: Vec<ShieldModeStatusData>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShieldModeStatusData {
+    pub is_active: bool,
+}
+
+pub async fn get_shield_mode(
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+    operator_id: &str,
+) -> Result<ShieldModeStatus, HelixError> {
+    helix
+        .send_json(|c| {
+            c.get(TWITCH_SHIELD_MODE_API_URL).query(&[
+                ("broadcaster_id", broadcaster_id),
+                ("moderator_id", operator_id),
+            ])
+        })
+        .await
+}
+
+#[derive(Serialize)]
+struct ShieldModeUpdate {
+    is_active: bool,
+}
+
+pub async fn update_shield_mode(
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+    operator_id: &str,
+    is_active: bool,
+) -> Result<(), HelixError> {
+    helix
+        .send(|c| {
+            c.put(TWITCH_SHIELD_MODE_API_URL)
+                .query(&[
+                    ("broadcaster_id", broadcaster_id),
+                    ("moderator_id", operator_id),
+                ])
+                .json(&ShieldModeUpdate { is_active })
+        })
+        .await?;
+    Ok(())
 }
 
 #[derive(Deserialize, Serialize)]
+pub struct Streams {
+    pub data: Vec<StreamData>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StreamData {
+    pub id: String,
+    pub title: String,
+    pub started_at: String,
+}
+
+#[derive(Serialize)]
+struct ChatMessageBody<'a> {
+    broadcaster_id: &'a str,
+    sender_id: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_parent_message_id: Option<&'a str>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SentChatMessage {
+    pub message_id: String,
+    pub is_sent: bool,
+    pub drop_reason: Option<DropReason>,
+}
+
+/// AutoMod やチャットの制限で投稿が落とされた理由。
+#[derive(Deserialize, Debug)]
+pub struct DropReason {
+    pub code: String,
+    pub message: String,
+}
+
+/// `sender_id` として `broadcaster_id` のチャットへ投稿する。落とされても 200 が返るので
+/// `is_sent` を見ること。
+pub async fn send_chat_message(
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+    sender_id: &str,
+    message: &str,
+    reply_to: Option<&str>,
+) -> Result<Option<SentChatMessage>, HelixError> {
+    let body = ChatMessageBody {
+        broadcaster_id,
+        sender_id,
+        message,
+        reply_parent_message_id: reply_to,
+    };
+    let page: Page<SentChatMessage> = helix
+        .send_json(|c| c.post(TWITCH_CHAT_MESSAGES_API_URL).json(&body))
+        .await?;
+    Ok(page.data.into_iter().next())
+}
+
+/// 配信中なら `data` に 1 件、オフラインなら空で返る。
+pub async fn get_streams(helix: &mut HelixClient, user_id: &str) -> Result<Streams, HelixError> {
+    helix
+        .send_json(|c| c.get(TWITCH_STREAMS_API_URL).query(&[("user_id", user_id)]))
+        .await
+}
+
+#[derive(Serialize)]
 struct EventSubSubscription<'a> {
     #[serde(rename = "type")]
     type_: &'a str,
     version: &'a str,
-    #[serde(borrow)]
     condition: EventSubCondition<'a>,
-    #[serde(borrow)]
     transport: EventSubTransport<'a>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// 購読種別ごとに条件のキーが違う（`channel.raid` は `to_broadcaster_user_id`）ため、
+/// 使わないキーは送らない。
+#[derive(Serialize)]
 struct EventSubCondition<'a> {
-    broadcaster_user_id: &'a str,
-    moderator_user_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broadcaster_user_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moderator_user_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_broadcaster_user_id: Option<&'a str>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 struct EventSubTransport<'a> {
     method: &'a str,
     session_id: &'a str,
 }
 
+/// `moderator_id` は `channel.follow` のようにモデレーター権限を要する購読でだけ渡す。
 pub async fn sub_event(
-    operator_id: &str,
+    helix: &mut HelixClient,
+    sub_type: &str,
+    version: &str,
+    broadcaster_id: &str,
+    moderator_id: Option<&str>,
     session_id: &str,
-    access_token: &str,
-    client_id: &str,
-) -> Result<String, reqwest::Error> {
-    let headers = auth_headers(access_token, client_id);
+) -> Result<String, HelixError> {
     let sub = EventSubSubscription {
-        type_: "channel.follow",
-        version: "2",
+        type_: sub_type,
+        version,
         condition: EventSubCondition {
-            broadcaster_user_id: operator_id,
-            moderator_user_id: operator_id,
+            broadcaster_user_id: Some(broadcaster_id),
+            moderator_user_id: moderator_id,
+            to_broadcaster_user_id: None,
         },
         transport: EventSubTransport {
             method: "websocket",
             session_id,
         },
     };
-    let res = HTTP_CLIENT
-        .post(TWITCH_SUB_EVENT_API_URL)
-        .headers(headers)
-        .json(&sub)
-        .send()
+    post_event_sub(helix, &sub).await
+}
+
+/// `broadcaster_id` へのレイド（`channel.raid` の受け側）を購読する。
+pub async fn sub_raid_event(
+    helix: &mut HelixClient,
+    broadcaster_id: &str,
+    session_id: &str,
+) -> Result<String, HelixError> {
+    let sub = EventSubSubscription {
+        type_: "channel.raid",
+        version: "1",
+        condition: EventSubCondition {
+            broadcaster_user_id: None,
+            moderator_user_id: None,
+            to_broadcaster_user_id: Some(broadcaster_id),
+        },
+        transport: EventSubTransport {
+            method: "websocket",
+            session_id,
+        },
+    };
+    post_event_sub(helix, &sub).await
+}
+
+async fn post_event_sub(
+    helix: &mut HelixClient,
+    sub: &EventSubSubscription<'_>,
+) -> Result<String, HelixError> {
+    let res = helix
+        .send(|c| c.post(TWITCH_SUB_EVENT_API_URL).json(sub))
         .await?
-        .error_for_status()?
         .text()
         .await?;
     Ok(res)
 }
 
-fn auth_headers(access_token: &str, client_id: &str) -> HeaderMap {
+/// 購読一覧の最初のページに載る合計値。
+#[derive(Deserialize, Default)]
+pub struct EventSubTotals {
+    pub total: i64,
+    pub total_cost: i64,
+    pub max_total_cost: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventSubSubscriptionInfo {
+    pub id: String,
+    pub status: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub version: String,
+    pub cost: i64,
+    pub created_at: String,
+    pub transport: EventSubTransportInfo,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventSubTransportInfo {
+    pub method: String,
+    pub session_id: Option<String>,
+    pub callback: Option<String>,
+}
+
+/// 全ページを辿って登録済みの購読を集める。
+pub async fn get_event_subs(
+    helix: &mut HelixClient,
+) -> Result<(Vec<EventSubSubscriptionInfo>, EventSubTotals), HelixError> {
+    let (subs, totals) = get_pages_with(helix, TWITCH_SUB_EVENT_API_URL, &[], None).await?;
+    Ok((subs, totals.unwrap_or_default()))
+}
+
+pub async fn delete_event_sub(helix: &mut HelixClient, id: &str) -> Result<(), HelixError> {
+    helix
+        .send(|c| c.delete(TWITCH_SUB_EVENT_API_URL).query(&[("id", id)]))
+        .await?;
+    Ok(())
+}
+
+pub fn auth_headers(access_token: &str, client_id: &str) -> HeaderMap {
     let mut headers = HeaderMap::new();
     headers.append(
         "Authorization",
@@ -303,3 +743,89 @@ fn auth_headers(access_token: &str, client_id: &str) -> HeaderMap {
     headers.append("Client-Id", client_id.parse::<HeaderValue>().unwrap());
     headers
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_keeps_fields_besides_data_and_pagination() {
+        let page: Page<serde_json::Value, EventSubTotals> = serde_json::from_str(
+            r#"{"data": [{}], "total": 3, "total_cost": 2, "max_total_cost": 10,
+                "pagination": {"cursor": "abc"}}"#,
+        )
+        .unwrap();
+        assert_eq!(page.data.len(), 1);
+        assert_eq!(page.pagination.next(), Some("abc"));
+        assert_eq!(
+            (
+                page.extra.total,
+                page.extra.total_cost,
+                page.extra.max_total_cost
+            ),
+            (3, 2, 10)
+        );
+        let last: Page<serde_json::Value> =
+            serde_json::from_str(r#"{"data": [], "pagination": {}}"#).unwrap();
+        assert_eq!(last.pagination.next(), None);
+    }
+
+    #[test]
+    fn device_poll_classifies_oauth_errors() {
+        assert_eq!(
+            DeviceTokenPoll::from_error_message(String::from("authorization_pending")),
+            DeviceTokenPoll::Pending
+        );
+        assert_eq!(
+            DeviceTokenPoll::from_error_message(String::from("slow_down")),
+            DeviceTokenPoll::SlowDown
+        );
+        assert_eq!(
+            DeviceTokenPoll::from_error_message(String::from("invalid device code")),
+            DeviceTokenPoll::Failed(String::from("invalid device code"))
+        );
+    }
+
+    #[test]
+    fn raid_condition_sends_only_to_broadcaster() {
+        let condition = EventSubCondition {
+            broadcaster_user_id: None,
+            moderator_user_id: None,
+            to_broadcaster_user_id: Some("123"),
+        };
+        assert_eq!(
+            serde_json::to_value(&condition).unwrap(),
+            serde_json::json!({"to_broadcaster_user_id": "123"})
+        );
+    }
+
+    #[test]
+    fn login_query_repeats_login_key() {
+        let logins = vec![String::from("a"), String::from("b")];
+        assert_eq!(login_query(&logins), vec![("login", "a"), ("login", "b")]);
+    }
+
+    #[test]
+    fn last_page_has_no_next_cursor() {
+        let page: Page<Chatter> =
+            serde_json::from_str(r#"{"data": [], "pagination": {}}"#).unwrap();
+        assert_eq!(page.pagination.next(), None);
+        let page: Page<Chatter> =
+            serde_json::from_str(r#"{"data": [], "pagination": {"cursor": ""}}"#).unwrap();
+        assert_eq!(page.pagination.next(), None);
+        // pagination ごと省略する応答もある
+        let page: Page<Chatter> = serde_json::from_str(r#"{"data": []}"#).unwrap();
+        assert_eq!(page.pagination.next(), None);
+    }
+
+    #[test]
+    fn middle_page_returns_cursor() {
+        let page: Page<Chatter> = serde_json::from_str(
+            r#"{"data": [{"user_id": "1", "user_login": "a", "user_name": "A"}],
+                "pagination": {"cursor": "eyJiIjpudWxs"}}"#,
+        )
+        .unwrap();
+        assert_eq!(page.data.len(), 1);
+        assert_eq!(page.pagination.next(), Some("eyJiIjpudWxs"));
+    }
+}
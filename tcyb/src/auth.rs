@@ -1,62 +1,139 @@
-use crate::api::{get_tokens_by_code, get_tokens_by_refresh, TWITCH_OAUTH2_AUTHZ_URL};
-use crate::store::DBStore;
+use crate::api::{
+    get_device_code, get_tokens_by_code, poll_device_token, DeviceTokenPoll,
+    TWITCH_OAUTH2_AUTHZ_URL,
+};
+use crate::cipher::Cipher;
+use crate::settings::Settings;
+use crate::store::Store;
+use anyhow::bail;
 use axum::{
     extract::{Query, State},
     http::StatusCode,
-    response::{IntoResponse, Redirect},
+    response::{Html, IntoResponse, Redirect},
     routing::get,
     Router,
 };
-use jfs::Store;
-use log::{info, warn};
+use log::{debug, info, warn};
 use serde::Deserialize;
-use std::borrow::Cow;
 use std::net::ToSocketAddrs;
-use std::path::Path;
+use std::time::Duration;
 use std::{net::SocketAddr, path::PathBuf};
+use tokio::sync::{mpsc, oneshot};
+
+/// `slow_down` を受けたときに広げる問い合わせ間隔（RFC 8628 の既定値）。
+const DEVICE_SLOW_DOWN_SECS: u64 = 5;
+/// ブラウザでの認可を待つ上限。過ぎたらサーバーを止めて失敗扱いにする。
+const AUTH_CODE_TIMEOUT_SECS: u64 = 300;
 
+/// ローカルに認可用サーバーを立ててブラウザで認可してもらう。
+/// コールバックで 1 回トークン交換を終えたら（成否によらず）サーバーを止めて戻る。
+/// 得たトークンはストアの `account` に保存する。
 pub async fn auth_code_grant(
-    listen_addr: &str,
-    db_dir: &Path,
-    db_name: &str,
-    client_id: &str,
-    client_secret: &str,
+    settings: &Settings,
+    account: &str,
+    cipher: Option<Cipher>,
 ) -> anyhow::Result<()> {
+    let listen_addr = settings.listen_address.as_str();
+    let addr = match listen_addr.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => bail!("listen_address {} did not resolve", listen_addr),
+    };
+    let (done_tx, mut done_rx) = mpsc::channel(1);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let server_t = tokio::spawn(start_server(
-        listen_addr.to_socket_addrs()?.next().unwrap(),
+        addr,
         ServerState {
-            db_dir: db_dir.to_path_buf(),
-            db_name: db_name.to_string(),
-            client_id: client_id.to_string(),
-            client_secret: client_secret.to_string(),
+            db_dir: settings.db_dir.clone(),
+            db_name: settings.db_name.clone(),
+            account: account.to_string(),
+            cipher,
+            client_id: settings.client_id.clone(),
+            client_secret: settings.client_secret.clone(),
             listen_addr: listen_addr.to_string(),
+            scopes: settings.scopes.join(" "),
+            state: uuid::Uuid::new_v4().to_string(),
+            done: done_tx,
         },
+        shutdown_rx,
     ));
-    if webbrowser::open(&format!("http://{}", listen_addr)).is_ok() {
-        let t = server_t.await?;
-        t.err();
-    } else {
-        server_t.abort();
+    let url = format!("http://{}", listen_addr);
+    if webbrowser::open(&url).is_err() {
+        println!("ブラウザで {} を開いてください。", url);
+    }
+    let result =
+        tokio::time::timeout(Duration::from_secs(AUTH_CODE_TIMEOUT_SECS), done_rx.recv()).await;
+    let _ = shutdown_tx.send(());
+    server_t.await??;
+    match result {
+        Ok(Some(Ok(()))) => Ok(()),
+        Ok(Some(Err(msg))) => bail!(msg),
+        Ok(None) => bail!("auth server stopped before authorization completed"),
+        Err(_) => bail!(
+            "authorization was not completed within {}s",
+            AUTH_CODE_TIMEOUT_SECS
+        ),
+    }
+}
+
+/// ブラウザもコールバックも無い環境向けの Device Code Grant。
+/// 表示した URL を別の端末で開いてコードを入力してもらい、それまでトークンを問い合わせ続ける。
+pub async fn device_code_grant(
+    settings: &Settings,
+    account: &str,
+    cipher: Option<Cipher>,
+) -> anyhow::Result<()> {
+    let client_id = settings.client_id.as_str();
+    let scopes = settings.scopes.join(" ");
+    let device = get_device_code(client_id, &scopes).await?;
+    println!(
+        "{} を開き、コード {} を入力してください（{} 秒以内）。",
+        device.verification_uri, device.user_code, device.expires_in
+    );
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = Duration::from_secs(device.interval.max(1));
+    loop {
+        tokio::time::sleep(interval).await;
+        if tokio::time::Instant::now() >= deadline {
+            bail!(
+                "device code expired; run `tcyb auth-device --account {}` again",
+                account
+            );
+        }
+        match poll_device_token(client_id, &scopes, &device.device_code).await? {
+            DeviceTokenPoll::Granted {
+                access_token,
+                refresh_token,
+            } => {
+                crate::store::save_tokens(
+                    &settings.db_dir,
+                    &settings.db_name,
+                    account,
+                    cipher.as_ref(),
+                    access_token,
+                    refresh_token,
+                )?;
+                info!("tokens of account {} updated successfully", account);
+                return Ok(());
+            }
+            DeviceTokenPoll::Pending => debug!("device authorization pending"),
+            DeviceTokenPoll::SlowDown => {
+                interval += Duration::from_secs(DEVICE_SLOW_DOWN_SECS);
+                debug!("slow down: poll every {}s", interval.as_secs());
+            }
+            DeviceTokenPoll::Failed(message) => bail!("device authorization failed: {}", message),
+        }
     }
-    Ok(())
 }
 
 pub async fn refresh_token_grant(
-    db_dir: &Path,
-    db_name: &str,
-    client_id: &str,
-    client_secret: &str,
+    settings: &Settings,
+    account: &str,
+    cipher: Option<Cipher>,
 ) -> anyhow::Result<()> {
-    let db = Store::new(db_dir)?;
-    let obj = db.get::<DBStore>(db_name)?;
-    let (access_token, refresh_token) =
-        get_tokens_by_refresh(&obj.refresh_token, client_id, client_secret).await?;
-    let updated_obj = DBStore {
-        access_token,
-        refresh_token,
-        ..obj
-    };
-    db.save_with_id(&updated_obj, db_name)?;
+    let mut store = Store::new(&settings.db_dir, &settings.db_name, account, cipher)?;
+    store
+        .update_tokens(&settings.client_id, &settings.client_secret)
+        .await?;
     Ok(())
 }
 
@@ -64,83 +141,241 @@ pub async fn refresh_token_grant(
 struct ServerState {
     db_dir: PathBuf,
     db_name: String,
+    /// トークンを保存するアカウント名。
+    account: String,
+    cipher: Option<Cipher>,
     client_id: String,
     client_secret: String,
     listen_addr: String,
+    scopes: String,
+    /// 認可 URL に載せ、コールバックで照合する CSRF 対策の値。起動ごとに 1 つ。
+    state: String,
+    /// トークン交換の成否を auth_code_grant へ知らせる。
+    done: mpsc::Sender<Result<(), String>>,
 }
 
-async fn start_server(addr: SocketAddr, state: ServerState) -> Result<(), std::io::Error> {
+async fn start_server(
+    addr: SocketAddr,
+    state: ServerState,
+    shutdown: oneshot::Receiver<()>,
+) -> Result<(), std::io::Error> {
     let app = Router::new()
         .route("/", get(auth))
         .route("/callback", get(callback))
         .with_state(state);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = shutdown.await;
+        })
+        .await?;
     Ok(())
 }
 
-async fn auth(State(state): State<ServerState>) -> impl IntoResponse {
-    let state_id = &uuid::Uuid::new_v4().to_string();
-    let redirect_uri = &format!("http://{}/callback", state.listen_addr);
-    let params: Vec<(&str, &str)> = vec![
-        ("client_id", &state.client_id),
+fn authorize_url(client_id: &str, redirect_uri: &str, scopes: &str, state: &str) -> String {
+    let params = [
+        ("client_id", client_id),
         ("redirect_uri", redirect_uri),
         ("response_type", "code"),
-        (
-            "scope",
-            "chat:read chat:edit moderator:manage:banned_users channel:moderate moderator:read:chatters moderator:read:followers user:read:follows",
-        ),
+        ("scope", scopes),
         ("force_verify", "true"),
-        ("state", state_id),
+        ("state", state),
     ];
-    let queries = params
-        .iter()
-        .map(|p| format!("{}={}", p.0, p.1))
-        .collect::<Vec<_>>()
-        .join("&");
+    url::Url::parse_with_params(TWITCH_OAUTH2_AUTHZ_URL, params)
+        .expect("authorize URL is a valid constant")
+        .into()
+}
 
-    Redirect::to(format!("{}?{}", TWITCH_OAUTH2_AUTHZ_URL, queries).as_str()).into_response()
+async fn auth(State(state): State<ServerState>) -> impl IntoResponse {
+    let redirect_uri = format!("http://{}/callback", state.listen_addr);
+    Redirect::to(&authorize_url(
+        &state.client_id,
+        &redirect_uri,
+        &state.scopes,
+        &state.state,
+    ))
 }
 
+/// Twitch からのリダイレクト。拒否された場合は `code` の代わりに `error` が付く。
 #[derive(Debug, Deserialize, Default)]
 struct Callback {
-    code: String,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
 }
 
-async fn callback(code: Query<Callback>, State(state): State<ServerState>) -> impl IntoResponse {
-    match obtain_access_token(
-        &format!("http://{}/callback", state.listen_addr),
-        &code.code,
-        &state.client_id,
-        &state.client_secret,
-        &state.db_name,
-        state.db_dir,
-    )
-    .await
-    {
+#[derive(Debug, PartialEq, Eq)]
+enum CallbackCheck<'a> {
+    Code(&'a str),
+    /// 自分が発行していない state。別サイトからの誘導とみなし、交換せず待ち続ける。
+    StateMismatch,
+    /// ユーザーが拒否したなど、Twitch がエラーを返した。
+    Denied(String),
+    Missing,
+}
+
+fn check_callback<'a>(callback: &'a Callback, expected_state: &str) -> CallbackCheck<'a> {
+    if callback.state.as_deref() != Some(expected_state) {
+        return CallbackCheck::StateMismatch;
+    }
+    if let Some(error) = &callback.error {
+        let description = callback.error_description.as_deref().unwrap_or_default();
+        return CallbackCheck::Denied(format!("{error}: {description}"));
+    }
+    match &callback.code {
+        Some(code) => CallbackCheck::Code(code),
+        None => CallbackCheck::Missing,
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn page(status: StatusCode, title: &str, message: &str) -> (StatusCode, Html<String>) {
+    let body = format!(
+        "<!doctype html><html lang=\"ja\"><head><meta charset=\"utf-8\"><title>tcyb</title></head>\
+         <body><h1>{}</h1><p>{}</p></body></html>",
+        html_escape(title),
+        html_escape(message)
+    );
+    (status, Html(body))
+}
+
+async fn callback(
+    Query(callback): Query<Callback>,
+    State(state): State<ServerState>,
+) -> impl IntoResponse {
+    let code = match check_callback(&callback, &state.state) {
+        CallbackCheck::Code(code) => code,
+        CallbackCheck::StateMismatch => {
+            warn!("callback with unknown state: ignore it");
+            return page(
+                StatusCode::BAD_REQUEST,
+                "認可に失敗しました",
+                "state が一致しません。tcyb が開いたページから認可し直してください。",
+            );
+        }
+        CallbackCheck::Missing => {
+            return page(
+                StatusCode::BAD_REQUEST,
+                "認可に失敗しました",
+                "code がありません。",
+            );
+        }
+        CallbackCheck::Denied(reason) => {
+            let msg = format!("authorization denied: {reason}");
+            warn!("{}", msg);
+            let _ = state.done.send(Err(msg)).await;
+            return page(
+                StatusCode::FORBIDDEN,
+                "認可されませんでした",
+                "このタブを閉じてください。",
+            );
+        }
+    };
+    match obtain_access_token(&state, code).await {
         Ok(_) => {
-            let msg = "tokens updated successfully";
-            info!("{}", msg);
-            (StatusCode::OK, Cow::from(msg))
+            info!("tokens of account {} updated successfully", state.account);
+            let _ = state.done.send(Ok(())).await;
+            page(
+                StatusCode::OK,
+                "認可が完了しました",
+                "トークンを保存しました。このタブを閉じてください。",
+            )
         }
         Err(err) => {
             let msg = format!("failed to update tokens: {}", err);
             warn!("{}", msg);
-            (StatusCode::INTERNAL_SERVER_ERROR, Cow::from(msg))
+            let _ = state.done.send(Err(msg.clone())).await;
+            page(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "認可に失敗しました",
+                &msg,
+            )
         }
     }
 }
 
-async fn obtain_access_token(
-    redirect_uri: &str,
-    code: &str,
-    client_id: &str,
-    client_secret: &str,
-    db_name: &str,
-    db_dir: PathBuf,
-) -> anyhow::Result<()> {
+async fn obtain_access_token(state: &ServerState, code: &str) -> anyhow::Result<()> {
+    let redirect_uri = format!("http://{}/callback", state.listen_addr);
     let (access_token, refresh_token) =
-        get_tokens_by_code(redirect_uri, code, client_id, client_secret).await?;
-    crate::store::save_tokens(&db_dir, db_name, access_token, refresh_token)?;
+        get_tokens_by_code(&redirect_uri, code, &state.client_id, &state.client_secret).await?;
+    crate::store::save_tokens(
+        &state.db_dir,
+        &state.db_name,
+        &state.account,
+        state.cipher.as_ref(),
+        access_token,
+        refresh_token,
+    )?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn callback(code: Option<&str>, state: Option<&str>, error: Option<&str>) -> Callback {
+        Callback {
+            code: code.map(String::from),
+            state: state.map(String::from),
+            error: error.map(String::from),
+            error_description: error.map(|_| String::from("The user denied you access")),
+        }
+    }
+
+    #[test]
+    fn authorize_url_encodes_query() {
+        let url = authorize_url(
+            "cid",
+            "http://localhost:8000/callback",
+            "chat:read chat:edit",
+            "st",
+        );
+        assert_eq!(
+            url,
+            "https://id.twitch.tv/oauth2/authorize?client_id=cid\
+             &redirect_uri=http%3A%2F%2Flocalhost%3A8000%2Fcallback\
+             &response_type=code&scope=chat%3Aread+chat%3Aedit&force_verify=true&state=st"
+        );
+    }
+
+    #[test]
+    fn callback_requires_matching_state() {
+        assert_eq!(
+            check_callback(&callback(Some("c"), Some("other"), None), "st"),
+            CallbackCheck::StateMismatch
+        );
+        assert_eq!(
+            check_callback(&callback(Some("c"), None, None), "st"),
+            CallbackCheck::StateMismatch
+        );
+        assert_eq!(
+            check_callback(&callback(Some("c"), Some("st"), None), "st"),
+            CallbackCheck::Code("c")
+        );
+    }
+
+    #[test]
+    fn callback_reports_access_denied() {
+        assert_eq!(
+            check_callback(&callback(None, Some("st"), Some("access_denied")), "st"),
+            CallbackCheck::Denied(String::from("access_denied: The user denied you access"))
+        );
+        assert_eq!(
+            check_callback(&callback(None, Some("st"), None), "st"),
+            CallbackCheck::Missing
+        );
+    }
+
+    #[test]
+    fn page_escapes_message() {
+        let (_, Html(body)) = page(StatusCode::OK, "t", "<b>&</b>");
+        assert!(body.contains("&lt;b&gt;&amp;&lt;/b&gt;"));
+    }
+}
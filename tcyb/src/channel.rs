@@ -1,56 +1,170 @@
 use crate::api;
-use crate::store::Store;
-use anyhow::bail;
+use crate::helix::HelixClient;
+use crate::output::{csv_field, OutputFormat};
+use crate::settings::BanBotsSettings;
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::PathBuf;
 
+const BOT_LIST_URL: &str = "https://api.twitchinsights.net/v1/bots/all";
+const BOT_WHITELIST_URL: &str = "https://mreliasen.github.io/twitch-bot-list/whitelist.json";
+
+/// `ban-bots` の指定。
+pub struct BanBotsOptions {
+    /// BAN せずに対象と件数だけを出す。
+    pub dry_run: bool,
+    /// リモートの一覧の代わりに読むファイル。
+    pub from_file: Option<PathBuf>,
+    /// リモートの除外一覧（既知の無害なボット）の代わりに読むファイル。
+    pub allowlist_file: Option<PathBuf>,
+    /// 設定の `reason` より優先する BAN 理由。
+    pub reason: Option<String>,
+}
+
+/// 一覧の名前を、BAN する対象と飛ばすものに振り分けた結果。
+#[derive(Debug, Default, PartialEq)]
+struct Plan {
+    targets: Vec<String>,
+    allowlisted: Vec<String>,
+}
+
+/// 許可リストに載った名前を外す。比較は大文字小文字を区別せず、重複は 1 つにする。
+/// 許可リストが空のままでは自分のチャンネルのボット（Nightbot など）まで BAN するので、
+/// `dry_run` でなければ断る。
+fn plan(bot_names: &[String], allowlist: &HashSet<String>, dry_run: bool) -> anyhow::Result<Plan> {
+    if allowlist.is_empty() && !dry_run {
+        bail!(
+            "除外する一覧が空です。--allowlist-file か [ban_bots] allowlist を指定するか、\
+             --dry-run で確認してください"
+        );
+    }
+    let mut seen = HashSet::new();
+    let mut plan = Plan::default();
+    for name in bot_names {
+        let login = name.to_lowercase();
+        if !seen.insert(login.clone()) {
+            continue;
+        }
+        if allowlist.contains(&login) {
+            plan.allowlisted.push(login);
+        } else {
+            plan.targets.push(login);
+        }
+    }
+    Ok(plan)
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    banned: Vec<String>,
+    allowlisted: usize,
+    already_banned: usize,
+    not_found: usize,
+    failed: Vec<(String, String)>,
+}
+
+impl Report {
+    fn summary(&self, dry_run: bool) -> String {
+        let verb = if dry_run { "would ban" } else { "banned" };
+        format!(
+            "{} {}, skipped {} (allowlisted {}, already banned {}, not found {}), failed {}",
+            verb,
+            self.banned.len(),
+            self.allowlisted + self.already_banned + self.not_found,
+            self.allowlisted,
+            self.already_banned,
+            self.not_found,
+            self.failed.len()
+        )
+    }
+}
+
+/// `channel_name` のチャンネルで一覧のボットを BAN する。トークンの持ち主はそのチャンネルの
+/// 配信者かモデレーターでなければならない。
 pub async fn ban_bots(
-    db_dir: &Path,
-    db_name: &str,
-    username: &str,
-    client_id: &str,
+    helix: &mut HelixClient,
+    channel_name: &str,
+    settings: &BanBotsSettings,
+    options: &BanBotsOptions,
 ) -> anyhow::Result<()> {
-    let mut store = Store::new(db_dir, db_name)?;
-    let my_user_id = store.user_id(username, client_id).await?;
-    let bot_names = get_bots_list().await?;
-    for bot_name in bot_names {
-        match api::get_user(&bot_name, store.access_token(), client_id).await {
-            Ok(user) => {
-                if !user.data.is_empty() {
-                    info!("ban {}: {}", bot_name, user.data[0].id);
-                    match api::ban_user(
-                        &my_user_id,
-                        &user.data[0].id,
-                        store.access_token(),
-                        client_id,
-                    )
-                    .await
-                    {
-                        Ok(response) => {
-                            info!("banned {}: {} {}", bot_name, user.data[0].id, response)
-                        }
-                        Err(err) => {
-                            if err.status() == Some(reqwest::StatusCode::BAD_REQUEST) {
-                                warn!("failed to ban {}: {}", user.data[0].id, err);
-                            } else {
-                                bail!(err);
-                            }
-                        }
-                    };
-                } else {
-                    warn!("{} has no entry", bot_name);
-                }
+    let reason = options.reason.as_deref().unwrap_or(&settings.reason);
+    let moderator_id = helix.user_id().await?;
+    let channel_user = api::get_user(helix, channel_name).await?;
+    if channel_user.data.is_empty() {
+        bail!("channel not found");
+    }
+    let broadcaster_id = channel_user.data[0].id.clone();
+    let bot_names = match &options.from_file {
+        Some(path) => read_bots_file(path)?,
+        None => get_bots_list().await?,
+    };
+    let remote_allowlist = match &options.allowlist_file {
+        Some(path) => read_bots_file(path)?,
+        // 取れないまま進むと除外すべきボットまで BAN するので止める
+        None => get_whitelist().await.with_context(|| {
+            format!(
+                "既知の無害なボットの一覧 {BOT_WHITELIST_URL} を取得できません。\
+                 --allowlist-file で一覧のファイルを指定してください"
+            )
+        })?,
+    };
+    let allowlist: HashSet<String> = settings
+        .allowlist
+        .iter()
+        .chain(&remote_allowlist)
+        .map(|n| n.to_lowercase())
+        .collect();
+    let plan = plan(&bot_names, &allowlist, options.dry_run)?;
+    let mut report = Report {
+        allowlisted: plan.allowlisted.len(),
+        ..Report::default()
+    };
+    let banned: HashSet<String> = api::get_banned_users(helix, &broadcaster_id)
+        .await?
+        .into_iter()
+        .map(|u| u.user_id)
+        .collect();
+    let users = api::get_users(helix, &plan.targets).await?;
+    let found: HashSet<String> = users.iter().map(|u| u.login.to_lowercase()).collect();
+    for bot_name in plan.targets.iter().filter(|n| !found.contains(*n)) {
+        warn!("{} has no entry", bot_name);
+        report.not_found += 1;
+    }
+    for user in users {
+        if banned.contains(&user.id) {
+            info!("{} is already banned", user.login);
+            report.already_banned += 1;
+            continue;
+        }
+        if options.dry_run {
+            println!("would ban {} ({})", user.login, user.id);
+            report.banned.push(user.login);
+            continue;
+        }
+        info!("ban {}: {}", user.login, user.id);
+        match api::ban_user(helix, &broadcaster_id, &moderator_id, &user.id, reason).await {
+            Ok(response) => {
+                info!("banned {}: {} {}", user.login, user.id, response);
+                report.banned.push(user.login);
             }
             Err(err) => {
-                if err.status() == Some(reqwest::StatusCode::NOT_FOUND) {
-                    warn!("username {}: {}", bot_name, err);
-                } else {
+                // 認可の失敗は残りも全部失敗するので打ち切る
+                if matches!(err.status().map(|s| s.as_u16()), Some(401) | Some(403)) {
+                    println!("{}", report.summary(false));
                     bail!(err);
                 }
+                warn!("failed to ban {}: {}", user.login, err);
+                report.failed.push((user.login, err.to_string()));
             }
-        }
+        };
+    }
+    for (login, err) in &report.failed {
+        println!("failed {}: {}", login, err);
     }
+    println!("{}", report.summary(options.dry_run));
     Ok(())
 }
 
@@ -67,73 +181,184 @@ struct BotList {
     _total: i64,
 }
 
+/// ボット一覧のファイルを読む。`BOT_LIST_URL` の応答をそのまま保存した JSON、
+/// 名前の JSON 配列、1 行 1 名のテキストのいずれでもよい。`#` で始まる行は読み飛ばす。
+fn parse_bot_list(text: &str) -> Vec<String> {
+    if let Ok(list) = serde_json::from_str::<BotList>(text) {
+        return list.bots.into_iter().map(|b| b.name).collect();
+    }
+    if let Ok(names) = serde_json::from_str::<Vec<String>>(text) {
+        return names;
+    }
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+fn read_bots_file(path: &std::path::Path) -> anyhow::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("{} を読めません", path.display()))?;
+    Ok(parse_bot_list(&text))
+}
+
+/// リモートのボット一覧。
 async fn get_bots_list() -> Result<Vec<String>, reqwest::Error> {
-    let (all_bots_res, white_bots_res) = tokio::join!(
-        reqwest::Client::new()
-            .get("https://api.twitchinsights.net/v1/bots/all")
-            .send()
-            .await?
-            .json::<BotList>(),
-        reqwest::Client::new()
-            .get("https://mreliasen.github.io/twitch-bot-list/whitelist.json")
-            .send()
-            .await?
-            .json::<Vec<String>>()
-    );
-    let all_bots = all_bots_res?;
-    let white_bots = white_bots_res?;
-    let black_bot_names = all_bots
-        .bots
-        .iter()
-        .map(|n| n.name.clone())
-        .filter(|b| !white_bots.contains(b))
-        .collect::<Vec<_>>();
-    Ok(black_bot_names)
-}
-
-async fn follows(
-    user_id: &str,
-    after: &str,
-    access_token: &str,
-    client_id: &str,
-) -> Result<Vec<String>, reqwest::Error> {
-    let followed_users = api::get_followed(user_id, &100, after, access_token, client_id).await?;
-    let user_ids = match followed_users.pagination.cursor {
-        Some(after) => Box::pin(follows(user_id, &after, access_token, client_id)).await?,
-        None => vec![],
-    };
-    let new_user_ids: Vec<String> = followed_users
-        .data
-        .iter()
-        .map(|x| x.broadcaster_id.clone())
-        .collect();
-    Ok([new_user_ids, user_ids].concat())
+    let all_bots = reqwest::Client::new()
+        .get(BOT_LIST_URL)
+        .send()
+        .await?
+        .json::<BotList>()
+        .await?;
+    Ok(all_bots.bots.into_iter().map(|b| b.name).collect())
+}
+
+/// 除外する既知の無害なボットの一覧。
+async fn get_whitelist() -> Result<Vec<String>, reqwest::Error> {
+    reqwest::Client::new()
+        .get(BOT_WHITELIST_URL)
+        .send()
+        .await?
+        .json::<Vec<String>>()
+        .await
+}
+
+/// `show-followings` の指定。
+pub struct FollowingsOptions {
+    pub format: OutputFormat,
+    /// 新しい順にこの件数までで打ち切る。
+    pub limit: Option<usize>,
+}
+
+/// フォロー日時をローカル時刻で表示する。読めなければ Twitch の値のまま。
+fn format_followed_at(followed_at: &str) -> String {
+    DateTime::parse_from_rfc3339(followed_at).map_or_else(
+        |_| followed_at.to_string(),
+        |t| {
+            t.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        },
+    )
+}
+
+fn format_followings(format: OutputFormat, followed: &[api::Followed]) -> String {
+    match format {
+        OutputFormat::Csv => followed
+            .iter()
+            .map(|f| {
+                format!(
+                    "{},{},{}",
+                    csv_field(&f.broadcaster_login),
+                    csv_field(&f.broadcaster_name),
+                    format_followed_at(&f.followed_at)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::Json => serde_json::to_string(followed).unwrap_or_default(),
+        OutputFormat::Table => {
+            let mut lines = vec![format!("{:<25}  {:<25}  FOLLOWED_AT", "LOGIN", "NAME")];
+            lines.extend(followed.iter().map(|f| {
+                format!(
+                    "{:<25}  {:<25}  {}",
+                    f.broadcaster_login,
+                    f.broadcaster_name,
+                    format_followed_at(&f.followed_at)
+                )
+            }));
+            lines.join("\n")
+        }
+    }
 }
 
+/// Get Followed Channels はトークンの持ち主の分しか返さないので、`username` は
+/// トークンのアカウントと一致している必要がある。
+/// トークンの持ち主がフォロー中のチャンネル。Get Followed Channels は他人の分を返さない。
 pub async fn show_following_info(
-    db_dir: &Path,
-    db_name: &str,
-    username: &str,
-    client_id: &str,
-    client_secret: &str,
+    helix: &mut HelixClient,
+    options: &FollowingsOptions,
 ) -> anyhow::Result<()> {
-    let mut store = Store::new(db_dir, db_name)?;
-    let user_id = store.user_id(username, client_id).await?;
-    loop {
-        match follows(&user_id, "", store.access_token(), client_id).await {
-            Ok(followed_users) => {
-                println!("{:?}", followed_users);
-                break;
-            }
-            Err(err) => {
-                if err.status() == Some(reqwest::StatusCode::UNAUTHORIZED) {
-                    warn!("refresh token: {}", err);
-                    store.update_tokens(client_id, client_secret).await?;
-                } else {
-                    bail!(err);
-                }
-            }
-        };
+    let user_id = helix.user_id().await?;
+    let followed = api::get_followed(helix, &user_id, options.limit).await?;
+    let out = format_followings(options.format, &followed);
+    if !out.is_empty() {
+        println!("{out}");
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn plan_drops_allowlisted_and_duplicate_names() {
+        let allowlist: HashSet<String> = [String::from("nightbot")].into();
+        let plan = plan(
+            &names(&["SpamBot", "Nightbot", "spambot", "other"]),
+            &allowlist,
+            false,
+        )
+        .unwrap();
+        assert_eq!(plan.targets, names(&["spambot", "other"]));
+        assert_eq!(plan.allowlisted, names(&["nightbot"]));
+    }
+
+    #[test]
+    fn plan_refuses_to_ban_without_an_allowlist() {
+        let bots = names(&["Nightbot", "spambot"]);
+        assert!(plan(&bots, &HashSet::new(), false).is_err());
+        let dry = plan(&bots, &HashSet::new(), true).unwrap();
+        assert_eq!(dry.targets, names(&["nightbot", "spambot"]));
+    }
+
+    #[test]
+    fn bot_list_file_accepts_saved_api_response_array_and_lines() {
+        let saved = r#"{"bots": [{"name": "a", "number": 1, "time": 2}], "_total": 1}"#;
+        assert_eq!(parse_bot_list(saved), names(&["a"]));
+        assert_eq!(parse_bot_list(r#"["a", "b"]"#), names(&["a", "b"]));
+        assert_eq!(parse_bot_list("# cached\na\n\n b \n"), names(&["a", "b"]));
+    }
+
+    #[test]
+    fn followings_show_login_name_and_time() {
+        let followed = vec![api::Followed {
+            broadcaster_id: String::from("1"),
+            broadcaster_login: String::from("alice"),
+            broadcaster_name: String::from("Alice, Inc"),
+            followed_at: String::from("not a time"),
+        }];
+        assert_eq!(
+            format_followings(OutputFormat::Csv, &followed),
+            "alice,\"Alice, Inc\",not a time"
+        );
+        let table = format_followings(OutputFormat::Table, &followed);
+        assert!(table.starts_with("LOGIN"));
+        assert!(table.lines().nth(1).unwrap().starts_with("alice "));
+        let json: serde_json::Value =
+            serde_json::from_str(&format_followings(OutputFormat::Json, &followed)).unwrap();
+        assert_eq!(json[0]["broadcaster_login"], "alice");
+        assert_eq!(json[0]["followed_at"], "not a time");
+    }
+
+    #[test]
+    fn summary_counts_each_skip_reason() {
+        let report = Report {
+            banned: names(&["a", "b"]),
+            allowlisted: 1,
+            already_banned: 2,
+            not_found: 3,
+            failed: vec![(String::from("c"), String::from("500"))],
+        };
+        assert_eq!(
+            report.summary(true),
+            "would ban 2, skipped 6 (allowlisted 1, already banned 2, not found 3), failed 1"
+        );
+    }
+}
@@ -1,92 +1,260 @@
-use crate::{api, store::Store};
+use crate::api::{self, Chatter};
+use crate::helix::HelixClient;
+use crate::output::{csv_field, OutputFormat};
 use anyhow::bail;
+use chrono::{DateTime, Local};
 use log::warn;
-use std::path::Path;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// `show-chatters` の指定。
+pub struct ChattersOptions {
+    pub format: OutputFormat,
+    /// 指定があればこの秒数ごとに取り直し、入退室を出し続ける。
+    pub watch: Option<u64>,
+    /// 取得したスナップショットを 1 行 1 件の JSON で追記するファイル。
+    pub snapshot_file: Option<PathBuf>,
+}
+
+/// ある時点のチャット参加者（配信者と bot 自身を除き、ログイン名順）。
+struct Snapshot {
+    time: DateTime<Local>,
+    chatters: Vec<Chatter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Join,
+    Leave,
+}
+
+impl Change {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Join => "join",
+            Self::Leave => "leave",
+        }
+    }
+}
+
+fn format_time(time: &DateTime<Local>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn format_snapshot(format: OutputFormat, snapshot: &Snapshot) -> String {
+    let time = format_time(&snapshot.time);
+    match format {
+        OutputFormat::Csv => std::iter::once(time)
+            .chain(snapshot.chatters.iter().map(|c| csv_field(&c.user_login)))
+            .collect::<Vec<_>>()
+            .join(","),
+        OutputFormat::Json => serde_json::json!({
+            "time": time,
+            "chatters": snapshot.chatters,
+        })
+        .to_string(),
+        OutputFormat::Table => {
+            let mut lines = vec![
+                format!("{} ({} chatters)", time, snapshot.chatters.len()),
+                format!("{:<25}  {:<25}  ID", "LOGIN", "NAME"),
+            ];
+            lines.extend(
+                snapshot
+                    .chatters
+                    .iter()
+                    .map(|c| format!("{:<25}  {:<25}  {}", c.user_login, c.user_name, c.user_id)),
+            );
+            lines.join("\n")
+        }
+    }
+}
+
+fn format_change(
+    format: OutputFormat,
+    time: &DateTime<Local>,
+    change: Change,
+    c: &Chatter,
+) -> String {
+    let time = format_time(time);
+    match format {
+        OutputFormat::Csv => format!("{},{},{}", time, change.label(), csv_field(&c.user_login)),
+        OutputFormat::Json => serde_json::json!({
+            "time": time,
+            "event": change.label(),
+            "chatter": c,
+        })
+        .to_string(),
+        OutputFormat::Table => format!("{}  {:<5}  {}", time, change.label(), c.user_login),
+    }
+}
+
+/// 前回から増えた人と減った人。どちらもログイン名順。
+fn diff<'a>(prev: &'a [Chatter], cur: &'a [Chatter]) -> Vec<(Change, &'a Chatter)> {
+    let logins = |list: &[Chatter]| -> BTreeSet<String> {
+        list.iter().map(|c| c.user_login.clone()).collect()
+    };
+    let (before, after) = (logins(prev), logins(cur));
+    let joined = cur
+        .iter()
+        .filter(|c| !before.contains(&c.user_login))
+        .map(|c| (Change::Join, c));
+    let left = prev
+        .iter()
+        .filter(|c| !after.contains(&c.user_login))
+        .map(|c| (Change::Leave, c));
+    joined.chain(left).collect()
+}
+
+fn append_snapshot(path: &Path, snapshot: &Snapshot) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", format_snapshot(OutputFormat::Json, snapshot))
+}
+
+async fn fetch_snapshot(
+    helix: &mut HelixClient,
+    channel_user_id: &str,
+    user_id: &str,
+    excluded: &[&str],
+) -> anyhow::Result<Snapshot> {
+    let mut chatters: Vec<Chatter> = api::get_chatters(helix, channel_user_id, user_id)
+        .await?
+        .into_iter()
+        .filter(|c| !excluded.contains(&c.user_login.as_str()))
+        .collect();
+    chatters.sort_by(|a, b| a.user_login.cmp(&b.user_login));
+    Ok(Snapshot {
+        time: chrono::offset::Local::now(),
+        chatters,
+    })
+}
 
 pub async fn chatters(
-    db_dir: &Path,
-    db_name: &str,
+    helix: &mut HelixClient,
     channel_name: &str,
     username: &str,
-    client_id: &str,
-    client_secret: &str,
+    options: &ChattersOptions,
 ) -> anyhow::Result<()> {
-    let mut store = Store::new(db_dir, db_name)?;
-    let user_id = store.user_id(username, client_id).await?;
-    let channel_user_id;
-    loop {
-        match api::get_user(channel_name, store.access_token(), client_id).await {
-            Ok(channel_user) => {
-                if channel_user.data.is_empty() {
-                    bail!("channel not found");
-                }
-                channel_user_id = channel_user.data[0].id.clone();
-                break;
-            }
-            Err(err) => {
-                if err.status() == Some(reqwest::StatusCode::UNAUTHORIZED) {
-                    warn!("refresh token: {}", err);
-                    store.update_tokens(client_id, client_secret).await?;
-                } else {
-                    bail!(err);
-                }
-            }
-        };
+    let user_id = helix.user_id().await?;
+    let channel_user = api::get_user(helix, channel_name).await?;
+    if channel_user.data.is_empty() {
+        bail!("channel not found");
     }
+    let channel_user_id = channel_user.data[0].id.clone();
+    let excluded = [channel_name, username];
+    let mut prev = fetch_snapshot(helix, &channel_user_id, &user_id, &excluded).await?;
+    println!("{}", format_snapshot(options.format, &prev));
+    if let Some(path) = &options.snapshot_file {
+        append_snapshot(path, &prev)?;
+    }
+    let Some(interval_secs) = options.watch else {
+        return Ok(());
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
     loop {
-        match api::get_chatters(&channel_user_id, &user_id, store.access_token(), client_id).await {
-            Ok(res) => {
-                let t = chrono::offset::Local::now();
-                let mut users: Vec<String> = res
-                    .data
-                    .iter()
-                    .map(|c| c.user_login.clone())
-                    .filter(|name| name != channel_name && name != username)
-                    .collect();
-                users.sort();
-                let t_formatted = format!("{}", t.format("%Y-%m-%d %H:%M:%S"));
-                println!("{},{}", t_formatted, users.join(","));
-                break;
+        interval.tick().await;
+        // 一時的な失敗で見張りを止めない。次の周期で取り直す
+        let cur = match fetch_snapshot(helix, &channel_user_id, &user_id, &excluded).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("failed to get chatters: {}", e);
+                continue;
             }
-            Err(err) => {
-                if err.status() == Some(reqwest::StatusCode::UNAUTHORIZED) {
-                    warn!("refresh token: {}", err);
-                    store.update_tokens(client_id, client_secret).await?;
-                } else {
-                    bail!(err);
-                }
+        };
+        for (change, chatter) in diff(&prev.chatters, &cur.chatters) {
+            println!(
+                "{}",
+                format_change(options.format, &cur.time, change, chatter)
+            );
+        }
+        if let Some(path) = &options.snapshot_file {
+            if let Err(e) = append_snapshot(path, &cur) {
+                warn!("failed to append snapshot to {}: {}", path.display(), e);
             }
         }
+        prev = cur;
     }
-    Ok(())
 }
 
-pub async fn show_user_info(
-    db_dir: &Path,
-    db_name: &str,
-    username: &str,
-    client_id: &str,
-    client_secret: &str,
-) -> anyhow::Result<()> {
-    let mut store = Store::new(db_dir, db_name)?;
-    loop {
-        match api::get_user(username, store.access_token(), client_id).await {
-            Ok(channel_user) => {
-                if channel_user.data.is_empty() {
-                    bail!("channel not found");
-                }
-                println!("{:?}", channel_user);
-                break;
-            }
-            Err(err) => {
-                if err.status() == Some(reqwest::StatusCode::UNAUTHORIZED) {
-                    warn!("refresh token: {}", err);
-                    store.update_tokens(client_id, client_secret).await?;
-                } else {
-                    bail!(err);
-                }
-            }
-        };
+pub async fn show_user_info(helix: &mut HelixClient, username: &str) -> anyhow::Result<()> {
+    let channel_user = api::get_user(helix, username).await?;
+    if channel_user.data.is_empty() {
+        bail!("channel not found");
     }
+    println!("{:?}", channel_user);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn chatter(login: &str) -> Chatter {
+        Chatter {
+            user_id: format!("id-{login}"),
+            user_login: login.to_string(),
+            user_name: login.to_uppercase(),
+        }
+    }
+
+    fn snapshot(logins: &[&str]) -> Snapshot {
+        Snapshot {
+            time: Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap(),
+            chatters: logins.iter().map(|l| chatter(l)).collect(),
+        }
+    }
+
+    #[test]
+    fn csv_snapshot_keeps_the_single_line_format() {
+        assert_eq!(
+            format_snapshot(OutputFormat::Csv, &snapshot(&["alice", "bob"])),
+            "2026-10-18 12:00:00,alice,bob"
+        );
+    }
+
+    #[test]
+    fn json_snapshot_lists_chatters() {
+        let line = format_snapshot(OutputFormat::Json, &snapshot(&["alice"]));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["time"], "2026-10-18 12:00:00");
+        assert_eq!(value["chatters"][0]["user_login"], "alice");
+    }
+
+    #[test]
+    fn diff_reports_joins_then_leaves() {
+        let prev = snapshot(&["alice", "bob"]);
+        let cur = snapshot(&["bob", "carol"]);
+
+        let changes: Vec<(Change, &str)> = diff(&prev.chatters, &cur.chatters)
+            .into_iter()
+            .map(|(change, c)| (change, c.user_login.as_str()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![(Change::Join, "carol"), (Change::Leave, "alice")]
+        );
+        assert_eq!(
+            format_change(OutputFormat::Csv, &cur.time, Change::Join, &cur.chatters[1]),
+            "2026-10-18 12:00:00,join,carol"
+        );
+    }
+
+    #[test]
+    fn snapshots_are_appended_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chatters.jsonl");
+
+        append_snapshot(&path, &snapshot(&["alice"])).unwrap();
+        append_snapshot(&path, &snapshot(&["alice", "bob"])).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+    }
+}
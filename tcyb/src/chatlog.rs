@@ -0,0 +1,311 @@
+//! 受け取ったチャットとイベントの記録と検索。
+//!
+//! 1 件を 1 行の JSON にして、日ごとのファイル（`chat-YYYY-MM-DD.jsonl`）へ追記する。
+//! 読み上げや翻訳の結果も同じ行に残すので、`tcyb logs search` で後から
+//! 「誰が何を言って、読まれたか」を辿れる。書き込みに失敗しても読み上げは止めない。
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const FILE_PREFIX: &str = "chat-";
+const FILE_SUFFIX: &str = ".jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    Chat,
+    Follow,
+    Raid,
+    StreamOnline,
+    StreamOffline,
+}
+
+/// 読み上げの結果。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeechOutcome {
+    Spoken,
+    /// 配信状態の連動で読み上げを止めていた。
+    Muted,
+    /// フォロー荒らし中などで読み上げなかった。
+    Skipped,
+    Failed(String),
+}
+
+impl SpeechOutcome {
+    pub fn from_result<E: std::fmt::Display>(result: &Result<SpeechOutcome, E>) -> Self {
+        match result {
+            Ok(outcome) => outcome.clone(),
+            Err(e) => Self::Failed(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// RFC 3339（ローカル時刻）。
+    pub time: String,
+    pub kind: RecordKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_login: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// IRC タグ（チャットのみ）。
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speech: Option<SpeechOutcome>,
+}
+
+impl Record {
+    pub fn new(kind: RecordKind) -> Self {
+        Self {
+            time: Local::now().to_rfc3339(),
+            kind,
+            user_id: None,
+            user_login: None,
+            user_name: None,
+            text: None,
+            tags: BTreeMap::new(),
+            translation: None,
+            speech: None,
+        }
+    }
+
+    fn parsed_time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.time).ok()
+    }
+}
+
+/// 日ごとの JSONL ファイルへの書き込み口。read-chat の全タスクで共有する。
+pub struct ChatLog {
+    dir: PathBuf,
+}
+
+impl ChatLog {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn file_for(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!(
+            "{FILE_PREFIX}{}{FILE_SUFFIX}",
+            date.format("%Y-%m-%d")
+        ))
+    }
+
+    fn try_append(&self, record: &Record) -> anyhow::Result<()> {
+        let date = record
+            .parsed_time()
+            .map_or_else(|| Local::now().date_naive(), |t| t.date_naive());
+        std::fs::create_dir_all(&self.dir)?;
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        // 1 行を 1 回の write で書くので、タスク間で行が混ざらない
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file_for(date))?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn append(&self, record: &Record) {
+        if let Err(e) = self.try_append(record) {
+            warn!("chat log: failed to write to {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// `tcyb logs search` の条件。指定の無い条件は絞り込まない。
+#[derive(Debug, Default)]
+pub struct Query {
+    /// ログイン名か表示名（大文字小文字を区別しない）。
+    pub user: Option<String>,
+    pub since: Option<DateTime<FixedOffset>>,
+    /// 本文か翻訳に含まれる文字列（大文字小文字を区別しない）。
+    pub text: Option<String>,
+}
+
+impl Query {
+    fn matches(&self, record: &Record) -> bool {
+        let contains = |field: &Option<String>, needle: &str| {
+            field
+                .as_deref()
+                .is_some_and(|v| v.to_lowercase().contains(needle))
+        };
+        let user_ok = self.user.as_deref().is_none_or(|user| {
+            let user = user.to_lowercase();
+            [&record.user_login, &record.user_name]
+                .iter()
+                .any(|f| f.as_deref().is_some_and(|v| v.to_lowercase() == user))
+        });
+        let since_ok = self
+            .since
+            .is_none_or(|since| record.parsed_time().is_some_and(|t| t >= since));
+        let text_ok = self.text.as_deref().is_none_or(|text| {
+            let text = text.to_lowercase();
+            contains(&record.text, &text) || contains(&record.translation, &text)
+        });
+        user_ok && since_ok && text_ok
+    }
+}
+
+/// `YYYY-MM-DD`（その日のローカル 0 時）か RFC 3339 の日時を読む。
+pub fn parse_since(s: &str) -> anyhow::Result<DateTime<FixedOffset>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t);
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .with_context(|| format!("{s:?} は YYYY-MM-DD か RFC 3339 の日時ではありません"))?;
+    let midnight = Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        .earliest()
+        .with_context(|| format!("{s:?} のローカル 0 時を決められません"))?;
+    Ok(midnight.fixed_offset())
+}
+
+/// ファイル名の日付。記録のファイルでなければ `None`。
+fn file_date(path: &Path) -> Option<NaiveDate> {
+    let name = path.file_name()?.to_str()?;
+    let date = name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX)?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// `dir` の記録から `query` に合うものを古い順に集める。`since` より前の日のファイルは読まない。
+pub fn search(dir: &Path, query: &Query) -> anyhow::Result<Vec<Record>> {
+    let mut files: Vec<(NaiveDate, PathBuf)> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter_map(|p| file_date(&p).map(|d| (d, p)))
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("{} を読めません", dir.display())),
+    };
+    files.sort();
+    let mut found = Vec::new();
+    for (date, path) in files {
+        // 日付はファイルを書いた側のローカル日付なので、1 日の余裕を持たせる
+        if query
+            .since
+            .is_some_and(|since| date < since.date_naive().pred_opt().unwrap_or(date))
+        {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("{} を読めません", path.display()))?;
+        for (i, line) in text.lines().enumerate() {
+            match serde_json::from_str::<Record>(line) {
+                Ok(record) if query.matches(&record) => found.push(record),
+                Ok(_) => {}
+                Err(e) => warn!("{}:{}: skip broken record: {}", path.display(), i + 1, e),
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// 検索結果の 1 行表示。
+pub fn format_record(record: &Record) -> String {
+    let kind = serde_json::to_value(record.kind)
+        .ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default();
+    let who = match (&record.user_name, &record.user_login) {
+        (Some(name), Some(login)) if !name.eq_ignore_ascii_case(login) => {
+            format!("{name}({login})")
+        }
+        (Some(name), _) => name.clone(),
+        (None, Some(login)) => login.clone(),
+        (None, None) => String::new(),
+    };
+    let mut line = format!("{}  {:<14}  {}", record.time, kind, who);
+    if let Some(text) = &record.text {
+        line.push_str(&format!(": {text}"));
+    }
+    if let Some(translation) = &record.translation {
+        line.push_str(&format!(" => {translation}"));
+    }
+    match &record.speech {
+        Some(SpeechOutcome::Failed(e)) => line.push_str(&format!(" [speech failed: {e}]")),
+        Some(SpeechOutcome::Muted) => line.push_str(" [muted]"),
+        Some(SpeechOutcome::Skipped) => line.push_str(" [skipped]"),
+        _ => {}
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(time: &str, login: &str, text: &str) -> Record {
+        Record {
+            time: time.to_string(),
+            user_login: Some(login.to_string()),
+            user_name: Some(login.to_uppercase()),
+            text: Some(text.to_string()),
+            speech: Some(SpeechOutcome::Spoken),
+            ..Record::new(RecordKind::Chat)
+        }
+    }
+
+    #[test]
+    fn records_rotate_per_day_and_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = ChatLog::new(dir.path().join("logs"));
+        let first = chat("2026-10-17T23:59:00+09:00", "alice", "hello");
+        let second = chat("2026-10-18T00:01:00+09:00", "bob", "こんにちは");
+
+        log.append(&first);
+        log.append(&second);
+
+        assert!(dir.path().join("logs/chat-2026-10-17.jsonl").is_file());
+        assert!(dir.path().join("logs/chat-2026-10-18.jsonl").is_file());
+        let all = search(&dir.path().join("logs"), &Query::default()).unwrap();
+        assert_eq!(all, vec![first, second]);
+    }
+
+    #[test]
+    fn query_filters_by_user_since_and_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = ChatLog::new(dir.path().to_path_buf());
+        log.append(&chat("2026-10-16T10:00:00+09:00", "alice", "old Hello"));
+        let mut translated = chat("2026-10-18T10:00:00+09:00", "alice", "こんにちは");
+        translated.translation = Some(String::from("Hello"));
+        log.append(&translated);
+        log.append(&chat("2026-10-18T11:00:00+09:00", "bob", "hello"));
+
+        let query = Query {
+            user: Some(String::from("ALICE")),
+            since: Some(parse_since("2026-10-17T00:00:00+09:00").unwrap()),
+            text: Some(String::from("hello")),
+        };
+        assert_eq!(search(dir.path(), &query).unwrap(), vec![translated]);
+    }
+
+    #[test]
+    fn missing_log_dir_is_an_empty_result() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(search(&dir.path().join("none"), &Query::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn since_accepts_a_plain_date() {
+        assert!(parse_since("2026-10-18").is_ok());
+        assert!(parse_since("yesterday").is_err());
+    }
+}
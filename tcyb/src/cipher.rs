@@ -0,0 +1,198 @@
+//! トークンストアの暗号化。
+//!
+//! パスフレーズから Argon2id で鍵を導出し、XChaCha20-Poly1305 で記録全体を封じる。
+//! KDF のパラメーターと salt は封じた記録と一緒に保存するので、既定値を変えても
+//! 既存のストアは開ける。
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+/// パスフレーズを渡す環境変数。未設定なら端末で尋ねる。
+pub const PASSPHRASE_ENV: &str = "TCYB_STORE_PASSPHRASE";
+const SALT_LEN: usize = 16;
+
+/// 封じた記録。JSON としてそのまま jfs に保存する。
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sealed {
+    kdf: KdfParams,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+}
+
+/// 導出済みの鍵。salt とパラメーターが同じ間は使い回し、Argon2 を毎回走らせない。
+struct Derived {
+    kdf: KdfParams,
+    key: Key,
+}
+
+#[derive(Clone)]
+pub struct Cipher {
+    passphrase: Arc<str>,
+    params: Params,
+    derived: Arc<Mutex<Option<Derived>>>,
+}
+
+fn invalid_data(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+impl Cipher {
+    pub fn new(passphrase: &str) -> Self {
+        Self::with_params(passphrase, Params::DEFAULT)
+    }
+
+    fn with_params(passphrase: &str, params: Params) -> Self {
+        Self {
+            passphrase: Arc::from(passphrase),
+            params,
+            derived: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// テストで Argon2 の既定コストを払わないための最小パラメーター。
+    #[cfg(test)]
+    pub fn cheap(passphrase: &str) -> Self {
+        Self::with_params(passphrase, Params::new(8, 1, 1, None).unwrap())
+    }
+
+    fn key_for(&self, kdf: &KdfParams) -> Result<Key, Error> {
+        let mut derived = self.derived.lock().unwrap();
+        if let Some(d) = derived.as_ref().filter(|d| &d.kdf == kdf) {
+            return Ok(d.key);
+        }
+        let salt = STANDARD
+            .decode(&kdf.salt)
+            .map_err(|e| invalid_data(e.to_string()))?;
+        let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+            .map_err(|e| invalid_data(e.to_string()))?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(self.passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| invalid_data(e.to_string()))?;
+        *derived = Some(Derived {
+            kdf: kdf.clone(),
+            key,
+        });
+        Ok(key)
+    }
+
+    /// 直前に開いた記録と同じ salt を使い、nonce だけ毎回新しくする。
+    fn current_kdf(&self) -> KdfParams {
+        if let Some(d) = self.derived.lock().unwrap().as_ref() {
+            return d.kdf.clone();
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        KdfParams {
+            m_cost: self.params.m_cost(),
+            t_cost: self.params.t_cost(),
+            p_cost: self.params.p_cost(),
+            salt: STANDARD.encode(salt),
+        }
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Sealed, Error> {
+        let kdf = self.current_kdf();
+        let key = self.key_for(&kdf)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&key)
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| invalid_data("failed to encrypt token store"))?;
+        Ok(Sealed {
+            kdf,
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        })
+    }
+
+    pub fn open(&self, sealed: &Sealed) -> Result<Vec<u8>, Error> {
+        let key = self.key_for(&sealed.kdf)?;
+        let nonce = STANDARD
+            .decode(&sealed.nonce)
+            .map_err(|e| invalid_data(e.to_string()))?;
+        if nonce.len() != 24 {
+            return Err(invalid_data("broken nonce in token store"));
+        }
+        let ciphertext = STANDARD
+            .decode(&sealed.ciphertext)
+            .map_err(|e| invalid_data(e.to_string()))?;
+        XChaCha20Poly1305::new(&key)
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| {
+                invalid_data("トークンストアを復号できません。パスフレーズが違う可能性があります。")
+            })
+    }
+}
+
+/// `TCYB_STORE_PASSPHRASE` があればそれを、無ければ端末で尋ねる。
+/// `confirm` なら打ち間違い防止にもう一度入力させる。
+pub fn passphrase(confirm: bool) -> Result<String, Error> {
+    if let Ok(p) = std::env::var(PASSPHRASE_ENV) {
+        if !p.is_empty() {
+            return Ok(p);
+        }
+    }
+    let p = rpassword::prompt_password("トークンストアのパスフレーズ: ")?;
+    if p.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "パスフレーズが空です"));
+    }
+    if confirm && rpassword::prompt_password("もう一度入力してください: ")? != p {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "パスフレーズが一致しません",
+        ));
+    }
+    Ok(p)
+}
+
+/// 設定で暗号化が有効なときだけパスフレーズを得て `Cipher` を作る。
+pub fn from_settings(settings: &crate::settings::Settings) -> Result<Option<Cipher>, Error> {
+    if !settings.encrypt_store {
+        return Ok(None);
+    }
+    Ok(Some(Cipher::new(&passphrase(false)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_round_trips() {
+        let cipher = Cipher::cheap("pass");
+        let sealed = cipher.seal(b"secret").unwrap();
+        assert!(!sealed.ciphertext.contains("secret"));
+        // 新しい Cipher（鍵キャッシュ無し）でも開ける
+        assert_eq!(Cipher::cheap("pass").open(&sealed).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn wrong_passphrase_is_invalid_data() {
+        let sealed = Cipher::cheap("pass").seal(b"secret").unwrap();
+        let err = Cipher::cheap("other").open(&sealed).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn nonce_changes_per_seal() {
+        let cipher = Cipher::cheap("pass");
+        let a = cipher.seal(b"secret").unwrap();
+        let b = cipher.seal(b"secret").unwrap();
+        assert_eq!(a.kdf, b.kdf);
+        assert_ne!(a.nonce, b.nonce);
+    }
+}
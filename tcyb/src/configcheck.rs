@@ -0,0 +1,401 @@
+//! `tcyb config check` — 設定の誤りを実行前に洗い出す。
+//!
+//! `operations` の書き間違いは最初のチャットで vstc のエラーとして、翻訳コマンドの不在は
+//! `warn!` として初めて表に出る。ここでは読み込んだ設定をキーごとに検査して一覧にし、
+//! エラーが 1 つでもあれば失敗で終わる。
+
+use crate::settings::{Settings, StreamStateAction, TimerSettings};
+use std::ffi::OsString;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Ok,
+    /// 動くが意図どおりでない可能性がある。
+    Warning,
+    /// このままでは該当機能が動かない。
+    Error,
+}
+
+impl Level {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub key: String,
+    pub level: Level,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(key: impl Into<String>, level: Level, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            level,
+            message: message.into(),
+        }
+    }
+}
+
+fn check_required(key: &str, value: &str) -> Finding {
+    if value.is_empty() {
+        Finding::new(key, Level::Error, "未設定です")
+    } else {
+        Finding::new(key, Level::Ok, "")
+    }
+}
+
+/// `operations` などの vstc route の並び。空なら何も送られない。
+fn check_routes(key: &str, ops: &[String], findings: &mut Vec<Finding>) {
+    if ops.is_empty() {
+        findings.push(Finding::new(
+            key,
+            Level::Warning,
+            "空です（何も送りません）",
+        ));
+        return;
+    }
+    for (i, op) in ops.iter().enumerate() {
+        let key = format!("{key}[{i}]");
+        findings.push(match vstc::parse_route(op) {
+            Ok(_) => Finding::new(key, Level::Ok, op.as_str()),
+            Err(e) => Finding::new(key, Level::Error, format!("{op:?}: {e}")),
+        });
+    }
+}
+
+/// `[chat]` などのセクションの `chains`。未記入なら `operations` を使うので検査しない。
+fn check_chains(key: &str, chains: &[Vec<String>], findings: &mut Vec<Finding>) {
+    for (i, chain) in chains.iter().enumerate() {
+        check_routes(&format!("{key}[{i}]"), chain, findings);
+    }
+}
+
+/// `cmd` を `PATH`（`path_var`）から探す。区切り文字を含めばそのパスだけを見る。
+/// Windows では `Command::new` と同じく `PATHEXT` の拡張子も試す。
+fn find_on_path(cmd: &str, path_var: Option<OsString>) -> Option<PathBuf> {
+    let candidates = |base: PathBuf| -> Vec<PathBuf> {
+        let mut c = vec![base.clone()];
+        if cfg!(windows) && base.extension().is_none() {
+            let exts = std::env::var("PATHEXT").unwrap_or_else(|_| String::from(".EXE;.CMD;.BAT"));
+            c.extend(
+                exts.split(';')
+                    .map(|e| base.with_extension(e.trim_start_matches('.'))),
+            );
+        }
+        c
+    };
+    if Path::new(cmd).components().count() > 1 {
+        return candidates(PathBuf::from(cmd))
+            .into_iter()
+            .find(|p| p.is_file());
+    }
+    std::env::split_paths(&path_var?)
+        .flat_map(|dir| candidates(dir.join(cmd)))
+        .find(|p| p.is_file())
+}
+
+fn check_translate_command(cmd: &str, path_var: Option<OsString>) -> Finding {
+    const KEY: &str = "translate_command";
+    if cmd.is_empty() {
+        return Finding::new(KEY, Level::Error, "未設定です");
+    }
+    match find_on_path(cmd, path_var) {
+        Some(path) => Finding::new(KEY, Level::Ok, path.display().to_string()),
+        None => Finding::new(
+            KEY,
+            Level::Error,
+            format!("{cmd:?} が PATH に見つかりません（翻訳返信が動きません）"),
+        ),
+    }
+}
+
+fn check_listen_address(addr: &str) -> Finding {
+    const KEY: &str = "listen_address";
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(_) => Finding::new(KEY, Level::Ok, addr),
+            None => Finding::new(KEY, Level::Error, format!("{addr:?} を解決できません")),
+        },
+        Err(e) => Finding::new(KEY, Level::Error, format!("{addr:?}: {e}")),
+    }
+}
+
+fn check_speech_address(addr: &str) -> Finding {
+    const KEY: &str = "speech_address";
+    match url::Url::parse(addr) {
+        Ok(_) => Finding::new(KEY, Level::Ok, addr),
+        Err(e) => Finding::new(KEY, Level::Error, format!("{addr:?}: {e}")),
+    }
+}
+
+/// テンプレートは `placeholder` を置き換えて使う。無ければ毎回同じ文になる。
+fn check_template(key: &str, template: &str, placeholder: &str) -> Finding {
+    if template.is_empty() {
+        Finding::new(key, Level::Warning, "空です（何も読み上げません）")
+    } else if !template.contains(placeholder) {
+        Finding::new(
+            key,
+            Level::Warning,
+            format!("{placeholder} を含みません（毎回同じ文を読み上げます）"),
+        )
+    } else {
+        Finding::new(key, Level::Ok, template)
+    }
+}
+
+/// `[[timers]]`。周期 0 は毎回の確認で投稿してしまい、送信数の制限に達する。
+fn check_timers(timers: &[TimerSettings], findings: &mut Vec<Finding>) {
+    for (i, timer) in timers.iter().enumerate() {
+        let key = format!("timers[{i}]");
+        findings.push(if timer.message.trim().is_empty() {
+            Finding::new(key, Level::Error, "message が空です")
+        } else if timer.interval_secs == 0 {
+            Finding::new(key, Level::Error, "interval_secs が 0 です")
+        } else {
+            Finding::new(key, Level::Ok, timer.message.as_str())
+        });
+    }
+}
+
+/// `[control]` と `[overlay]` の `listen_address`。書いていなければ開かないので検査しない。
+fn check_loopback_address(key: &str, address: Option<&str>, findings: &mut Vec<Finding>) {
+    let Some(address) = address else {
+        return;
+    };
+    findings.push(match crate::control::loopback_addr(key, address) {
+        Ok(addr) => Finding::new(key, Level::Ok, addr.to_string()),
+        Err(e) => Finding::new(key, Level::Error, e.to_string()),
+    });
+}
+
+/// 設定をキーごとに検査する。`path_var` は翻訳コマンドを探す `PATH`。
+pub fn check(settings: &Settings, path_var: Option<OsString>) -> Vec<Finding> {
+    let mut findings = vec![
+        check_required("client_id", &settings.client_id),
+        check_required("client_secret", &settings.client_secret),
+        check_required("channel", &settings.channel),
+        check_required("username", &settings.username),
+        check_speech_address(&settings.speech_address),
+        check_listen_address(&settings.listen_address),
+        check_translate_command(&settings.translate_command, path_var),
+        check_template(
+            "greeting_template",
+            &settings.greeting_template,
+            "user_name",
+        ),
+        check_template(
+            "follow_storm.summary_template",
+            &settings.follow_storm.summary_template,
+            "follow_count",
+        ),
+    ];
+    check_routes("operations", &settings.operations, &mut findings);
+    check_chains("chat.chains", &settings.chat.chains, &mut findings);
+    check_chains("follow.chains", &settings.follow.chains, &mut findings);
+    check_chains("raid.chains", &settings.raid.chains, &mut findings);
+    check_chains(
+        "greetings.chains",
+        &settings.greetings.chains,
+        &mut findings,
+    );
+    if !settings.raid.template.is_empty() {
+        findings.push(check_template(
+            "raid.template",
+            &settings.raid.template,
+            "from_name",
+        ));
+    }
+    for (key, template) in [
+        (
+            "greetings.first_template",
+            &settings.greetings.first_template,
+        ),
+        (
+            "greetings.returning_template",
+            &settings.greetings.returning_template,
+        ),
+    ] {
+        if !template.is_empty() {
+            findings.push(check_template(key, template, "user_name"));
+        }
+    }
+    check_timers(&settings.timers, &mut findings);
+    check_loopback_address(
+        "control.listen_address",
+        settings.control.listen_address.as_deref(),
+        &mut findings,
+    );
+    check_loopback_address(
+        "overlay.listen_address",
+        settings.overlay.listen_address.as_deref(),
+        &mut findings,
+    );
+    match settings.stream_state.action {
+        StreamStateAction::Pause => {
+            check_routes(
+                "stream_state.pause_operations",
+                &settings.stream_state.pause_operations,
+                &mut findings,
+            );
+            check_routes(
+                "stream_state.resume_operations",
+                &settings.stream_state.resume_operations,
+                &mut findings,
+            );
+        }
+        StreamStateAction::Announce if settings.stream_state.online_text.is_empty() => {
+            findings.push(Finding::new(
+                "stream_state.online_text",
+                Level::Warning,
+                "空です（配信開始を読み上げません）",
+            ));
+        }
+        _ => {}
+    }
+    findings
+}
+
+/// 一覧を表示し、エラーの数を返す。
+pub fn print_report(findings: &[Finding]) -> usize {
+    for f in findings {
+        if f.message.is_empty() {
+            println!("{:<8} {}", f.level.label(), f.key);
+        } else {
+            println!("{:<8} {}: {}", f.level.label(), f.key, f.message);
+        }
+    }
+    let count = |level| findings.iter().filter(|f| f.level == level).count();
+    let errors = count(Level::Error);
+    println!("{} errors, {} warnings", errors, count(Level::Warning));
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(translate_command: &Path) -> Settings {
+        Settings {
+            client_id: String::from("id"),
+            client_secret: String::from("secret"),
+            channel: String::from("ch"),
+            username: String::from("bot"),
+            speech_address: String::from("http://localhost:8080"),
+            listen_address: String::from("127.0.0.1:8000"),
+            operations: vec![String::from("o:/tts?i=1"), String::from("o:/play")],
+            greeting_template: String::from("user_name さん、ありがとう"),
+            translate_command: translate_command.display().to_string(),
+            ..Settings::default()
+        }
+    }
+
+    fn levels(findings: &[Finding], level: Level) -> Vec<&str> {
+        findings
+            .iter()
+            .filter(|f| f.level == level)
+            .map(|f| f.key.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn valid_settings_have_no_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let translate = dir.path().join("translate");
+        std::fs::write(&translate, "").unwrap();
+
+        let findings = check(&settings(&translate), None);
+        assert!(levels(&findings, Level::Error).is_empty(), "{findings:?}");
+    }
+
+    #[test]
+    fn each_bad_operation_is_reported_by_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let translate = dir.path().join("translate");
+        std::fs::write(&translate, "").unwrap();
+        let mut s = settings(&translate);
+        s.operations.push(String::from("o:/nosuchop"));
+
+        let findings = check(&s, None);
+        assert_eq!(levels(&findings, Level::Error), vec!["operations[2]"]);
+    }
+
+    #[test]
+    fn bad_route_in_event_chain_is_reported_by_chain_and_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let translate = dir.path().join("translate");
+        std::fs::write(&translate, "").unwrap();
+        let mut s = settings(&translate);
+        s.follow.chains = vec![
+            vec![String::from("o:/tts?i=3")],
+            vec![String::from("o:/subtitle"), String::from("o:/nosuchop")],
+        ];
+
+        let findings = check(&s, None);
+        assert_eq!(levels(&findings, Level::Error), vec!["follow.chains[1][1]"]);
+    }
+
+    #[test]
+    fn empty_or_zero_interval_timers_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let translate = dir.path().join("translate");
+        std::fs::write(&translate, "").unwrap();
+        let timer = |message: &str, interval_secs| TimerSettings {
+            message: message.to_string(),
+            interval_secs,
+            min_chat_messages: 0,
+            speak: false,
+        };
+        let mut s = settings(&translate);
+        s.timers = vec![timer("discord", 1200), timer("", 60), timer("x", 0)];
+
+        let findings = check(&s, None);
+        assert_eq!(
+            levels(&findings, Level::Error),
+            vec!["timers[1]", "timers[2]"]
+        );
+    }
+
+    #[test]
+    fn translate_command_is_searched_on_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("translate"), "").unwrap();
+        let path_var = std::env::join_paths([dir.path()]).unwrap();
+
+        assert_eq!(
+            find_on_path("translate", Some(path_var.clone())),
+            Some(dir.path().join("translate"))
+        );
+        assert_eq!(find_on_path("missing", Some(path_var)), None);
+        assert_eq!(
+            check_translate_command("translate", None).level,
+            Level::Error
+        );
+    }
+
+    #[test]
+    fn template_without_placeholder_is_a_warning() {
+        assert_eq!(
+            check_template("greeting_template", "ありがとう", "user_name").level,
+            Level::Warning
+        );
+        assert_eq!(
+            check_template("greeting_template", "user_name さん", "user_name").level,
+            Level::Ok
+        );
+    }
+
+    #[test]
+    fn unparsable_addresses_are_errors() {
+        assert_eq!(check_listen_address("localhost").level, Level::Error);
+        assert_eq!(check_speech_address("not a url").level, Level::Error);
+    }
+}
@@ -0,0 +1,439 @@
+//! read-chat を外から操作するローカル HTTP API（`[control]`）。
+//!
+//! 読み上げの一時停止・再開、読み上げ中の発話の打ち切り、発言者ごとのミュート、任意の文の
+//! 読み上げと、接続状態などの取得を受け付ける。bind できるのはループバックのアドレスだけで、
+//! `token` を設定すれば `Authorization: Bearer <token>` の無いリクエストを 401 で断る。
+//! ブラウザで開いたページからは叩かせないので、`Origin` の付いたリクエストと、`Host` が
+//! ループバックでないリクエスト（DNS rebinding）は token に関わらず 403 で断る。
+
+use crate::chatlog::SpeechOutcome;
+use crate::reload::LiveSettings;
+use crate::settings::ControlSettings;
+use crate::speech::{speak, SpeechEvent};
+use crate::stream::ReadingGate;
+use anyhow::{bail, Context};
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Local;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// `/status` に残す直近のエラーの数。
+const MAX_LAST_ERRORS: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Connection {
+    Irc,
+    EventSub,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LastError {
+    pub time: String,
+    pub source: &'static str,
+    pub message: String,
+}
+
+/// IRC・EventSub のタスクと制御 API で共有する実行時の状態。
+#[derive(Default)]
+pub struct Control {
+    /// 読み上げ中（vstreamer の応答待ち）の件数。
+    pending: AtomicUsize,
+    skip: Notify,
+    muted_users: Mutex<BTreeSet<String>>,
+    irc: AtomicBool,
+    eventsub: AtomicBool,
+    errors: Mutex<VecDeque<LastError>>,
+}
+
+/// 接続している間だけ生かしておく。タスクが終わるか abort されると未接続に戻る。
+pub struct Connected<'a> {
+    flag: &'a AtomicBool,
+}
+
+impl Drop for Connected<'_> {
+    fn drop(&mut self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+struct Pending<'a>(&'a AtomicUsize);
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Control {
+    /// `speech` を読み上げ中として数えて待つ。`skip` されたら待つのをやめて `Skipped`。
+    pub async fn speak<F, E>(&self, speech: F) -> Result<SpeechOutcome, E>
+    where
+        F: Future<Output = Result<(), E>>,
+    {
+        // 数える前に待ち受けておけば、数えた直後の skip も取りこぼさない
+        let skipped = self.skip.notified();
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let _pending = Pending(&self.pending);
+        tokio::select! {
+            result = speech => result.map(|()| SpeechOutcome::Spoken),
+            () = skipped => Ok(SpeechOutcome::Skipped),
+        }
+    }
+
+    /// 読み上げ中のものをすべて打ち切る。打ち切った件数を返す。
+    pub fn skip(&self) -> usize {
+        let pending = self.pending.load(Ordering::SeqCst);
+        self.skip.notify_waiters();
+        pending
+    }
+
+    pub fn is_user_muted(&self, login: &str) -> bool {
+        self.muted_users
+            .lock()
+            .unwrap()
+            .contains(&login.to_lowercase())
+    }
+
+    fn set_user_muted(&self, login: &str, muted: bool) {
+        let mut users = self.muted_users.lock().unwrap();
+        if muted {
+            users.insert(login.to_lowercase());
+        } else {
+            users.remove(&login.to_lowercase());
+        }
+    }
+
+    pub fn connected(&self, connection: Connection) -> Connected<'_> {
+        let flag = match connection {
+            Connection::Irc => &self.irc,
+            Connection::EventSub => &self.eventsub,
+        };
+        flag.store(true, Ordering::SeqCst);
+        Connected { flag }
+    }
+
+    pub fn record_error(&self, source: &'static str, message: impl std::fmt::Display) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == MAX_LAST_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(LastError {
+            time: Local::now().to_rfc3339(),
+            source,
+            message: message.to_string(),
+        });
+    }
+
+    fn status(&self, reading: &ReadingGate) -> Status {
+        Status {
+            paused: reading.is_paused(),
+            stream_muted: reading.is_stream_muted(),
+            irc_connected: self.irc.load(Ordering::SeqCst),
+            eventsub_connected: self.eventsub.load(Ordering::SeqCst),
+            queue_length: self.pending.load(Ordering::SeqCst),
+            muted_users: self.muted_users.lock().unwrap().iter().cloned().collect(),
+            last_errors: self.errors.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    /// 制御 API で一時停止している。
+    paused: bool,
+    /// 配信状態の連動で止めている。
+    stream_muted: bool,
+    irc_connected: bool,
+    eventsub_connected: bool,
+    queue_length: usize,
+    muted_users: Vec<String>,
+    last_errors: Vec<LastError>,
+}
+
+#[derive(Clone)]
+struct ServerState {
+    control: Arc<Control>,
+    reading: Arc<ReadingGate>,
+    live: LiveSettings,
+    token: Option<String>,
+}
+
+/// `address`（設定の `key`）を解決し、ループバックでなければ断る。
+pub fn loopback_addr(key: &str, address: &str) -> anyhow::Result<SocketAddr> {
+    let Some(addr) = address
+        .to_socket_addrs()
+        .with_context(|| format!("{key} {address:?} を解決できません"))?
+        .next()
+    else {
+        bail!("{key} {address:?} を解決できません");
+    };
+    if !addr.ip().is_loopback() {
+        bail!("{key} は localhost のアドレスにしてください（{addr}）");
+    }
+    Ok(addr)
+}
+
+/// 長さ以外から一致した位置が分からないように、最後まで比べる。
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `token` が無ければ誰でも通す。
+fn authorized(token: Option<&str>, headers: &HeaderMap) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// `localhost` かループバックの IP（ポートは問わない）。
+fn is_loopback_host(host: &str) -> bool {
+    let Ok(authority) = host.parse::<axum::http::uri::Authority>() else {
+        return false;
+    };
+    let name = authority.host();
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// ブラウザのページから送られたものでないか。ページからの POST には `Origin` が付き、
+/// 別の名前をループバックへ向けたページ（DNS rebinding）では `Host` がその名前になる。
+fn from_local_client(headers: &HeaderMap, uri: &Uri) -> bool {
+    if headers.contains_key(header::ORIGIN) {
+        return false;
+    }
+    headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| uri.authority().map(|a| a.as_str()))
+        .is_some_and(is_loopback_host)
+}
+
+async fn guard(State(state): State<ServerState>, request: Request, next: Next) -> Response {
+    if !from_local_client(request.headers(), request.uri()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else if !authorized(state.token.as_deref(), request.headers()) {
+        StatusCode::UNAUTHORIZED.into_response()
+    } else {
+        next.run(request).await
+    }
+}
+
+async fn status(State(state): State<ServerState>) -> Json<Status> {
+    Json(state.control.status(&state.reading))
+}
+
+async fn pause(State(state): State<ServerState>) -> Json<Status> {
+    info!("control: reading paused");
+    state.reading.set_paused(true);
+    Json(state.control.status(&state.reading))
+}
+
+async fn resume(State(state): State<ServerState>) -> Json<Status> {
+    info!("control: reading resumed");
+    state.reading.set_paused(false);
+    Json(state.control.status(&state.reading))
+}
+
+async fn skip(State(state): State<ServerState>) -> Json<serde_json::Value> {
+    let skipped = state.control.skip();
+    info!("control: skipped {} speech", skipped);
+    Json(serde_json::json!({ "skipped": skipped }))
+}
+
+async fn mute_user(Path(login): Path<String>, State(state): State<ServerState>) -> Json<Status> {
+    info!("control: mute {}", login);
+    state.control.set_user_muted(&login, true);
+    Json(state.control.status(&state.reading))
+}
+
+async fn unmute_user(Path(login): Path<String>, State(state): State<ServerState>) -> Json<Status> {
+    info!("control: unmute {}", login);
+    state.control.set_user_muted(&login, false);
+    Json(state.control.status(&state.reading))
+}
+
+#[derive(Deserialize)]
+struct SayBody {
+    text: String,
+}
+
+/// `text` をチャットの読み上げ経路へ流す。一時停止中でも読み上げる。
+async fn say(State(state): State<ServerState>, Json(body): Json<SayBody>) -> Response {
+    if body.text.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": "text is empty" })),
+        )
+            .into_response();
+    }
+    info!("control: say {:?}", body.text);
+    let settings = state.live.borrow().clone();
+    let outcome = state
+        .control
+        .speak(speak(
+            &settings.speech_address,
+            &settings.chains(SpeechEvent::Chat),
+            body.text,
+        ))
+        .await;
+    match outcome {
+        Ok(outcome) => Json(serde_json::json!({ "speech": outcome })).into_response(),
+        Err(e) => {
+            state.control.record_error("control", &e);
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(serde_json::json!({ "error": e.to_string() })),
+            )
+                .into_response()
+        }
+    }
+}
+
+fn router(state: ServerState) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/skip", post(skip))
+        .route("/mute/{login}", post(mute_user).delete(unmute_user))
+        .route("/say", post(say))
+        .layer(middleware::from_fn_with_state(state.clone(), guard))
+        .with_state(state)
+}
+
+/// 設定があれば制御 API を開いてタスクとして動かす。bind の失敗は起動の失敗として返す。
+pub async fn start(
+    settings: &ControlSettings,
+    control: Arc<Control>,
+    reading: Arc<ReadingGate>,
+    live: LiveSettings,
+) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let Some(address) = &settings.listen_address else {
+        return Ok(None);
+    };
+    let addr = loopback_addr("control.listen_address", address)?;
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("control API を {addr} で開けません"))?;
+    info!("control API listening on http://{}", addr);
+    let state = ServerState {
+        control,
+        reading,
+        live,
+        token: settings.token.clone(),
+    };
+    Ok(Some(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router(state)).await {
+            warn!("control API stopped: {}", e);
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_token_is_required_only_when_set() {
+        let mut headers = HeaderMap::new();
+        assert!(authorized(None, &headers));
+        assert!(!authorized(Some("secret"), &headers));
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!authorized(Some("secret"), &headers));
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(authorized(Some("secret"), &headers));
+        headers.insert(header::AUTHORIZATION, "Bearer secre".parse().unwrap());
+        assert!(!authorized(Some("secret"), &headers));
+    }
+
+    #[test]
+    fn browser_pages_and_foreign_hosts_are_refused() {
+        let uri = Uri::from_static("/pause");
+        let mut headers = HeaderMap::new();
+        assert!(!from_local_client(&headers, &uri));
+        for host in ["127.0.0.1:8091", "localhost:8091", "[::1]:8091"] {
+            headers.insert(header::HOST, host.parse().unwrap());
+            assert!(from_local_client(&headers, &uri), "{host}");
+        }
+        headers.insert(header::ORIGIN, "https://example.com".parse().unwrap());
+        assert!(!from_local_client(&headers, &uri));
+        headers.remove(header::ORIGIN);
+        headers.insert(header::HOST, "rebind.example.com:8091".parse().unwrap());
+        assert!(!from_local_client(&headers, &uri));
+        assert!(from_local_client(
+            &HeaderMap::new(),
+            &Uri::from_static("http://127.0.0.1:8091/pause")
+        ));
+    }
+
+    #[test]
+    fn only_loopback_addresses_are_accepted() {
+        assert!(loopback_addr("control.listen_address", "127.0.0.1:8091").is_ok());
+        assert!(loopback_addr("control.listen_address", "[::1]:8091").is_ok());
+        assert!(loopback_addr("control.listen_address", "0.0.0.0:8091").is_err());
+    }
+
+    #[tokio::test]
+    async fn skip_ends_pending_speech() {
+        let control = Arc::new(Control::default());
+        let waiting = {
+            let control = control.clone();
+            tokio::spawn(async move {
+                control
+                    .speak(std::future::pending::<Result<(), vstc::VstcError>>())
+                    .await
+            })
+        };
+        while control.pending.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(control.skip(), 1);
+        assert_eq!(waiting.await.unwrap().unwrap(), SpeechOutcome::Skipped);
+        assert_eq!(control.pending.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn status_reports_connections_mutes_and_recent_errors() {
+        let control = Control::default();
+        let reading = ReadingGate::default();
+        let irc = control.connected(Connection::Irc);
+        control.set_user_muted("Alice", true);
+        for i in 0..=MAX_LAST_ERRORS {
+            control.record_error("irc", i);
+        }
+
+        let status = control.status(&reading);
+        assert!(status.irc_connected);
+        assert!(!status.eventsub_connected);
+        assert_eq!(status.muted_users, vec![String::from("alice")]);
+        assert_eq!(status.last_errors.len(), MAX_LAST_ERRORS);
+        assert_eq!(status.last_errors[0].message, "1");
+        assert!(control.is_user_muted("ALICE"));
+
+        drop(irc);
+        assert!(!control.status(&reading).irc_connected);
+    }
+}
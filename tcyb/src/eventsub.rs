@@ -1,7 +1,22 @@
-use crate::api::sub_event;
+use crate::api::{sub_event, sub_raid_event};
+use crate::chatlog::{ChatLog, Record, RecordKind, SpeechOutcome};
+use crate::control::{Connection, Control};
+use crate::followstorm::{
+    disable_mitigation, enable_mitigation, summary_text, FollowAction, SharedFollowStorm,
+    TickAction,
+};
+use crate::greeting::Greeter;
+use crate::helix::{HelixError, SharedHelix};
+use crate::overlay::{FeedItem, Overlay};
+use crate::reload::LiveSettings;
+use crate::settings::StreamStateAction;
+use crate::speech::{raid_text, speak, SpeechEvent};
+use crate::stream::ReadingGate;
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, info, warn};
 use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
 use tokio_tungstenite::{
     connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
@@ -19,64 +34,103 @@ pub enum EventSubError {
     ConnectionError(#[from] tokio_tungstenite::tungstenite::Error),
 }
 
-#[allow(clippy::too_many_arguments)]
+/// EventSub の通知処理に必要な値一式。接続ごとに yomiage が組み立てて渡す。
+/// 読み上げ先・経路・テンプレートは `live` から通知ごとに読むので、実行中の設定の
+/// 読み直しがそのまま効く。
+pub struct EventContext {
+    pub helix: SharedHelix,
+    pub user_id: String,
+    pub live: LiveSettings,
+    pub follow_storm: SharedFollowStorm,
+    pub reading: Arc<ReadingGate>,
+    pub chat_log: Arc<ChatLog>,
+    pub greeter: Arc<Greeter>,
+    pub control: Arc<Control>,
+    pub overlay: Arc<Overlay>,
+}
+
+impl EventContext {
+    /// 記録に残し、表示にも流す。
+    fn record(&self, record: &Record) {
+        self.overlay.publish(&FeedItem::event(record));
+        self.chat_log.append(record);
+    }
+}
+
 pub async fn sub_event_client_loop(
     url: Url,
-    access_token: String,
-    user_id: String,
-    client_id: String,
-    address: String,
-    operations: Vec<String>,
-    greeting_template: String,
+    ctx: EventContext,
     timeout_sec: u64,
 ) -> Result<(), EventSubError> {
     info!("connect event sub");
     let (mut ws_stream, _) = connect_async(url)
         .instrument(tracing::info_span!("event_connect"))
         .await?;
-    while let Ok(Some(msg)) = tokio::time::timeout(
-        std::time::Duration::from_secs(timeout_sec),
-        ws_stream.next(),
-    )
-    .await
-    {
-        let msg = msg?;
-        if let Err(e) = process_message(
-            &mut ws_stream,
-            msg,
-            &address,
-            &operations,
-            &user_id,
-            &access_token,
-            &client_id,
-            &greeting_template,
-        )
-        .await
-        {
-            match e {
-                MessageError::SessionReconnect { reconnect_url } => {
-                    warn!("session reconnect {}: try to reconnect.", reconnect_url);
-                    return Err(EventSubError::SessionReconnect { reconnect_url });
-                }
-                MessageError::ConnectionError(e) => {
-                    warn!("connection error {}: try to reconnect.", e);
-                    return Err(EventSubError::MessageConnectionError);
-                }
-                MessageError::SerializeError(e) => {
-                    warn!("msg serialization error {}: try to reconnect.", e);
-                    return Err(EventSubError::MessageConnectionError);
-                }
-                MessageError::RequestError(e) => {
-                    warn!("msg request error {}: try to reconnect.", e);
-                    return Err(EventSubError::MessageConnectionError);
+    let _connected = ctx.control.connected(Connection::EventSub);
+    let idle_timeout = std::time::Duration::from_secs(timeout_sec);
+    let mut storm_tick = tokio::time::interval(ctx.follow_storm.lock().unwrap().window());
+    storm_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    storm_tick.tick().await;
+    let mut last_received = tokio::time::Instant::now();
+    loop {
+        let elapsed = last_received.elapsed();
+        if elapsed >= idle_timeout {
+            return Ok(());
+        }
+        let remaining = idle_timeout - elapsed;
+        tokio::select! {
+            res = tokio::time::timeout(remaining, ws_stream.next()) => {
+                let Ok(Some(msg)) = res else {
+                    return Ok(());
+                };
+                last_received = tokio::time::Instant::now();
+                let msg = msg?;
+                if let Err(e) = process_message(&mut ws_stream, msg, &ctx).await {
+                    if !matches!(e, MessageError::SessionReconnect { .. }) {
+                        ctx.control.record_error("eventsub", &e);
+                    }
+                    if let Some(err) = reconnect_reason(e) {
+                        return Err(err);
+                    }
                 }
-                MessageError::VstcError(e) => {
+            }
+            _ = storm_tick.tick() => {
+                if let Err(e) = on_storm_tick(&ctx).await {
                     warn!("vstc error {}: ignore it.", e);
+                    ctx.control.record_error("speech", e);
                 }
             }
         }
     }
-    Ok(())
+}
+
+/// 接続を張り直すべきエラーなら再接続理由を返す。読み上げ失敗だけは握りつぶす。
+fn reconnect_reason(e: MessageError) -> Option<EventSubError> {
+    match e {
+        MessageError::SessionReconnect { reconnect_url } => {
+            warn!("session reconnect {}: try to reconnect.", reconnect_url);
+            Some(EventSubError::SessionReconnect { reconnect_url })
+        }
+        MessageError::ConnectionError(e) => {
+            warn!("connection error {}: try to reconnect.", e);
+            Some(EventSubError::MessageConnectionError)
+        }
+        MessageError::SerializeError(e) => {
+            warn!("msg serialization error {}: try to reconnect.", e);
+            Some(EventSubError::MessageConnectionError)
+        }
+        MessageError::HelixError(e) => {
+            if matches!(e.status().map(|s| s.as_u16()), Some(403) | Some(409)) {
+                warn!("subscription rejected; inspect existing ones with `tcyb eventsub list`");
+            }
+            warn!("msg request error {}: try to reconnect.", e);
+            Some(EventSubError::MessageConnectionError)
+        }
+        MessageError::VstcError(e) => {
+            warn!("vstc error {}: ignore it.", e);
+            None
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -103,9 +157,19 @@ struct Session {
     reconnect_url: Option<String>,
 }
 
-#[derive(Deserialize)]
+/// 購読種別ごとに載るフィールドが違うため、使うものだけを任意項目で受ける。
+#[derive(Deserialize, Default)]
 struct Event {
-    user_name: String,
+    /// `stream.online` では配信の ID。
+    id: Option<String>,
+    user_id: Option<String>,
+    user_login: Option<String>,
+    user_name: Option<String>,
+    started_at: Option<String>,
+    from_broadcaster_user_id: Option<String>,
+    from_broadcaster_user_login: Option<String>,
+    from_broadcaster_user_name: Option<String>,
+    viewers: Option<u64>,
 }
 
 #[derive(Error, Debug)]
@@ -117,21 +181,15 @@ enum MessageError {
     #[error(transparent)]
     SerializeError(#[from] serde_json::Error),
     #[error(transparent)]
-    RequestError(#[from] reqwest::Error),
+    HelixError(#[from] HelixError),
     #[error(transparent)]
     VstcError(#[from] vstc::VstcError),
 }
 
-#[allow(clippy::too_many_arguments)]
 async fn process_message(
     ws_stream: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
     msg: Message,
-    address: &str,
-    operations: &[String],
-    user_id: &str,
-    access_token: &str,
-    client_id: &str,
-    greeting_template: &str,
+    ctx: &EventContext,
 ) -> Result<(), MessageError> {
     if msg.is_ping() {
         debug!("ping");
@@ -149,7 +207,7 @@ async fn process_message(
                     None => String::from(""),
                 };
                 info!("session welcome {}", session_id);
-                sub_event(user_id, session_id.as_str(), access_token, client_id)
+                subscribe(ctx, &session_id)
                     .instrument(tracing::info_span!("event_subscribe"))
                     .await?;
                 crate::profiling::mark_ready(crate::profiling::Component::Event);
@@ -164,27 +222,7 @@ async fn process_message(
                 Err(MessageError::SessionReconnect { reconnect_url })
             }
             "notification" => match event_msg.metadata.subscription_type {
-                Some(s) => match s.as_str() {
-                    "channel.follow" => {
-                        let user_name = match event_msg.payload.event {
-                            Some(e) => e.user_name,
-                            None => String::from("Unknown user"),
-                        };
-                        info!("received follow notification {}", user_name);
-                        send_greeting_message_to_speak(
-                            user_name.as_str(),
-                            address,
-                            operations,
-                            greeting_template,
-                        )
-                        .await?;
-                        Ok(())
-                    }
-                    _ => {
-                        info!("received {}", msg_str);
-                        Ok(())
-                    }
-                },
+                Some(s) => on_notification(&s, event_msg.payload.event, &msg_str, ctx).await,
                 None => Ok(()),
             },
             _ => {
@@ -197,13 +235,207 @@ async fn process_message(
     }
 }
 
-async fn send_greeting_message_to_speak(
-    user_name: &str,
-    uri: &str,
-    operations: &[String],
-    text_template: &str,
-) -> Result<(), vstc::VstcError> {
-    let greeting = text_template.replace("user_name", user_name);
-    vstc::process_command(uri, operations, greeting, None, None, None).await?;
+/// 購読種別ごとの通知を処理する。
+async fn on_notification(
+    sub_type: &str,
+    event: Option<Event>,
+    msg_str: &str,
+    ctx: &EventContext,
+) -> Result<(), MessageError> {
+    let event = event.unwrap_or_default();
+    match sub_type {
+        "channel.follow" => {
+            let user_name = event
+                .user_name
+                .clone()
+                .unwrap_or(String::from("Unknown user"));
+            info!("received follow notification {}", user_name);
+            let outcome = on_follow(&user_name, ctx).await;
+            ctx.record(&Record {
+                user_id: event.user_id,
+                user_login: event.user_login,
+                user_name: event.user_name,
+                speech: Some(SpeechOutcome::from_result(&outcome)),
+                ..Record::new(RecordKind::Follow)
+            });
+            outcome?;
+        }
+        "channel.raid" => {
+            let from_name = event
+                .from_broadcaster_user_name
+                .clone()
+                .unwrap_or(String::from("Unknown user"));
+            let viewers = event.viewers.unwrap_or_default();
+            info!(
+                "received raid notification {} ({} viewers)",
+                from_name, viewers
+            );
+            let outcome = on_raid(&from_name, viewers, ctx).await;
+            ctx.record(&Record {
+                user_id: event.from_broadcaster_user_id,
+                user_login: event.from_broadcaster_user_login,
+                user_name: event.from_broadcaster_user_name,
+                text: Some(format!("{viewers} viewers")),
+                speech: Some(SpeechOutcome::from_result(&outcome)),
+                ..Record::new(RecordKind::Raid)
+            });
+            outcome?;
+        }
+        "stream.online" => {
+            let started_at = event.started_at.unwrap_or_default();
+            info!("stream went online at {}", started_at);
+            if let Some(id) = &event.id {
+                ctx.greeter.start_stream(id);
+            }
+            ctx.record(&Record {
+                text: Some(started_at),
+                ..Record::new(RecordKind::StreamOnline)
+            });
+            on_stream_state(true, ctx).await?;
+        }
+        "stream.offline" => {
+            info!("stream went offline");
+            ctx.record(&Record::new(RecordKind::StreamOffline));
+            on_stream_state(false, ctx).await?;
+        }
+        _ => info!("received {}", msg_str),
+    }
+    Ok(())
+}
+
+/// follow と raid は常に購読し、stream.online / stream.offline は設定が動作を持つときか、
+/// 挨拶のために配信の切り替わりを知る必要があるときだけ購読する。
+async fn subscribe(ctx: &EventContext, session_id: &str) -> Result<(), HelixError> {
+    let mut helix = ctx.helix.lock().await;
+    sub_event(
+        &mut helix,
+        "channel.follow",
+        "2",
+        &ctx.user_id,
+        Some(&ctx.user_id),
+        session_id,
+    )
+    .await?;
+    sub_raid_event(&mut helix, &ctx.user_id, session_id).await?;
+    let watch_stream = {
+        let settings = ctx.live.borrow();
+        settings.stream_state.action != StreamStateAction::None || settings.greetings.enabled()
+    };
+    if watch_stream {
+        for sub_type in ["stream.online", "stream.offline"] {
+            sub_event(&mut helix, sub_type, "1", &ctx.user_id, None, session_id).await?;
+        }
+    }
     Ok(())
 }
+
+async fn on_stream_state(online: bool, ctx: &EventContext) -> Result<(), vstc::VstcError> {
+    let settings = ctx.live.borrow().clone();
+    crate::stream::apply(
+        &settings.stream_state,
+        online,
+        &ctx.reading,
+        &settings.speech_address,
+        &settings.chains(SpeechEvent::Chat),
+    )
+    .await
+}
+
+async fn on_follow(user_name: &str, ctx: &EventContext) -> Result<SpeechOutcome, vstc::VstcError> {
+    let (action, mitigation, window) = {
+        let mut storm = ctx.follow_storm.lock().unwrap();
+        (
+            storm.on_follow(Instant::now()),
+            storm.mitigation(),
+            storm.window(),
+        )
+    };
+    match action {
+        FollowAction::Greet => {
+            if ctx.reading.is_muted() {
+                info!("reading is muted: skip greeting for {}", user_name);
+                return Ok(SpeechOutcome::Muted);
+            }
+            let settings = ctx.live.borrow().clone();
+            let greeting = settings.greeting_template.replace("user_name", user_name);
+            ctx.control
+                .speak(speak(
+                    &settings.speech_address,
+                    &settings.chains(SpeechEvent::Follow),
+                    greeting,
+                ))
+                .await
+        }
+        FollowAction::StormStarted => {
+            warn!(
+                "follow storm detected (>= threshold follows within {}s): suppress greetings",
+                window.as_secs()
+            );
+            let mitigated =
+                enable_mitigation(mitigation, &mut *ctx.helix.lock().await, &ctx.user_id).await;
+            ctx.follow_storm.lock().unwrap().set_mitigated(mitigated);
+            Ok(SpeechOutcome::Skipped)
+        }
+        FollowAction::Suppressed => {
+            info!("follow storm: greeting for {} suppressed", user_name);
+            Ok(SpeechOutcome::Skipped)
+        }
+    }
+}
+
+async fn on_storm_tick(ctx: &EventContext) -> Result<(), vstc::VstcError> {
+    let settings = ctx.live.borrow().clone();
+    let (action, summary, mitigation, mitigated) = {
+        let mut storm = ctx.follow_storm.lock().unwrap();
+        let action = storm.tick(Instant::now());
+        let summary = match action {
+            TickAction::Summary(n) | TickAction::StormEnded(n) if n > 0 => {
+                Some(summary_text(&settings.follow_storm.summary_template, n))
+            }
+            _ => None,
+        };
+        let mitigated = matches!(action, TickAction::StormEnded(_)) && storm.take_mitigated();
+        (action, summary, storm.mitigation(), mitigated)
+    };
+    if let TickAction::StormEnded(n) = action {
+        info!("follow storm subsided ({} follows since last summary)", n);
+        if mitigated {
+            disable_mitigation(mitigation, &mut *ctx.helix.lock().await, &ctx.user_id).await;
+        }
+    }
+    if let Some(text) = summary {
+        info!("follow storm summary: {}", text);
+        if !ctx.reading.is_muted() {
+            ctx.control
+                .speak(speak(
+                    &settings.speech_address,
+                    &settings.chains(SpeechEvent::Follow),
+                    text,
+                ))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn on_raid(
+    from_name: &str,
+    viewers: u64,
+    ctx: &EventContext,
+) -> Result<SpeechOutcome, vstc::VstcError> {
+    let settings = ctx.live.borrow().clone();
+    if settings.raid.template.is_empty() {
+        return Ok(SpeechOutcome::Skipped);
+    }
+    if ctx.reading.is_muted() {
+        info!("reading is muted: skip raid announcement for {}", from_name);
+        return Ok(SpeechOutcome::Muted);
+    }
+    ctx.control
+        .speak(speak(
+            &settings.speech_address,
+            &settings.chains(SpeechEvent::Raid),
+            raid_text(&settings.raid.template, from_name, viewers),
+        ))
+        .await
+}
@@ -0,0 +1,285 @@
+//! follow-bot 攻撃（短時間の大量フォロー）の検出と緩和。
+//!
+//! 直近 `window_secs` 秒のフォロー数がしきい値に達したら「荒らし中」とみなし、
+//! 1 件ごとの挨拶を止めて `summary_template` による件数サマリにまとめる。
+//! 設定に応じて荒らしの間だけフォロワー限定モード / シールドモードを Helix で有効化し、
+//! 収まったら自分が有効化したものだけを元に戻す。
+
+use crate::api;
+use crate::helix::{HelixClient, HelixError};
+use crate::settings::{FollowStormSettings, Mitigation};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// `summary_template` の `follow_count` を件数に置き換える。
+pub fn summary_text(template: &str, count: usize) -> String {
+    template.replace("follow_count", &count.to_string())
+}
+
+/// EventSub の再接続をまたいで状態を保つため、yomiage 側で 1 つ作って共有する。
+pub type SharedFollowStorm = Arc<Mutex<FollowStorm>>;
+
+/// フォロー 1 件を受けたときに取るべき行動。
+#[derive(Debug, PartialEq, Eq)]
+pub enum FollowAction {
+    /// 通常どおり 1 件ずつ挨拶する。
+    Greet,
+    /// このフォローでしきい値に達した。挨拶せず緩和策を発動する。
+    StormStarted,
+    /// 荒らし中。挨拶せず件数だけ数える。
+    Suppressed,
+}
+
+/// 定期 tick で取るべき行動。
+#[derive(Debug, PartialEq, Eq)]
+pub enum TickAction {
+    Idle,
+    /// 荒らし継続中。前回以降に抑止した件数をまとめて読み上げる。
+    Summary(usize),
+    /// 荒らしが収まった。残りの件数を読み上げ、緩和策を解除する。
+    StormEnded(usize),
+}
+
+pub struct FollowStorm {
+    settings: FollowStormSettings,
+    recent: VecDeque<Instant>,
+    storming: bool,
+    pending: usize,
+    /// 緩和策を tcyb 自身が有効化したか。元から有効だったものは解除しない。
+    mitigated: bool,
+}
+
+impl FollowStorm {
+    pub fn new(settings: &FollowStormSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            recent: VecDeque::new(),
+            storming: false,
+            pending: 0,
+            mitigated: false,
+        }
+    }
+
+    pub fn shared(settings: &FollowStormSettings) -> SharedFollowStorm {
+        Arc::new(Mutex::new(Self::new(settings)))
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.settings.window_secs.max(1))
+    }
+
+    pub fn mitigation(&self) -> Mitigation {
+        self.settings.mitigation
+    }
+
+    fn enabled(&self) -> bool {
+        self.settings.threshold > 0
+    }
+
+    fn evict(&mut self, now: Instant) {
+        let window = self.window();
+        while let Some(&oldest) = self.recent.front() {
+            if now.saturating_duration_since(oldest) < window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    pub fn on_follow(&mut self, now: Instant) -> FollowAction {
+        if !self.enabled() {
+            return FollowAction::Greet;
+        }
+        self.evict(now);
+        self.recent.push_back(now);
+        if self.storming {
+            self.pending += 1;
+            return FollowAction::Suppressed;
+        }
+        if self.recent.len() >= self.settings.threshold {
+            self.storming = true;
+            self.pending = 1;
+            return FollowAction::StormStarted;
+        }
+        FollowAction::Greet
+    }
+
+    pub fn tick(&mut self, now: Instant) -> TickAction {
+        if !self.storming {
+            return TickAction::Idle;
+        }
+        self.evict(now);
+        let count = std::mem::take(&mut self.pending);
+        if self.recent.len() < self.settings.threshold {
+            self.storming = false;
+            TickAction::StormEnded(count)
+        } else if count > 0 {
+            TickAction::Summary(count)
+        } else {
+            TickAction::Idle
+        }
+    }
+
+    pub fn set_mitigated(&mut self, mitigated: bool) {
+        self.mitigated = mitigated;
+    }
+
+    pub fn take_mitigated(&mut self) -> bool {
+        std::mem::take(&mut self.mitigated)
+    }
+}
+
+/// 緩和策を有効化する。既に有効だった場合は触らず `false` を返し、
+/// 解除時に元の状態を壊さないようにする。失敗はログに残して読み上げは続ける。
+pub async fn enable_mitigation(
+    mitigation: Mitigation,
+    helix: &mut HelixClient,
+    user_id: &str,
+) -> bool {
+    let result = match mitigation {
+        Mitigation::None => return false,
+        Mitigation::FollowersOnly => enable_followers_only(helix, user_id).await,
+        Mitigation::ShieldMode => enable_shield_mode(helix, user_id).await,
+    };
+    match result {
+        Ok(changed) => changed,
+        Err(err) => {
+            warn!("follow storm: failed to enable {:?}: {}", mitigation, err);
+            false
+        }
+    }
+}
+
+async fn enable_followers_only(helix: &mut HelixClient, user_id: &str) -> Result<bool, HelixError> {
+    let current = api::get_chat_settings(helix, user_id, user_id).await?;
+    if current.data.first().is_some_and(|s| s.follower_mode) {
+        info!("follow storm: followers-only mode is already on, leave it as is");
+        return Ok(false);
+    }
+    api::update_follower_mode(helix, user_id, user_id, true).await?;
+    info!("follow storm: enabled followers-only mode");
+    Ok(true)
+}
+
+async fn enable_shield_mode(helix: &mut HelixClient, user_id: &str) -> Result<bool, HelixError> {
+    let current = api::get_shield_mode(helix, user_id, user_id).await?;
+    if current.data.first().is_some_and(|s| s.is_active) {
+        info!("follow storm: shield mode is already active, leave it as is");
+        return Ok(false);
+    }
+    api::update_shield_mode(helix, user_id, user_id, true).await?;
+    info!("follow storm: activated shield mode");
+    Ok(true)
+}
+
+/// [`enable_mitigation`] で有効化した緩和策を解除する。
+pub async fn disable_mitigation(mitigation: Mitigation, helix: &mut HelixClient, user_id: &str) {
+    let result = match mitigation {
+        Mitigation::None => return,
+        Mitigation::FollowersOnly => {
+            api::update_follower_mode(helix, user_id, user_id, false).await
+        }
+        Mitigation::ShieldMode => api::update_shield_mode(helix, user_id, user_id, false).await,
+    };
+    match result {
+        Ok(()) => info!("follow storm: disabled {:?}", mitigation),
+        Err(err) => warn!("follow storm: failed to disable {:?}: {}", mitigation, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storm(threshold: usize, window_secs: u64) -> FollowStorm {
+        FollowStorm::new(&FollowStormSettings {
+            threshold,
+            window_secs,
+            ..FollowStormSettings::default()
+        })
+    }
+
+    #[test]
+    fn greets_below_threshold() {
+        let mut s = storm(3, 10);
+        let t0 = Instant::now();
+        assert_eq!(s.on_follow(t0), FollowAction::Greet);
+        assert_eq!(
+            s.on_follow(t0 + Duration::from_secs(1)),
+            FollowAction::Greet
+        );
+        assert_eq!(s.tick(t0 + Duration::from_secs(2)), TickAction::Idle);
+    }
+
+    #[test]
+    fn follows_outside_window_do_not_accumulate() {
+        let mut s = storm(3, 10);
+        let t0 = Instant::now();
+        for i in 0..5 {
+            assert_eq!(
+                s.on_follow(t0 + Duration::from_secs(i * 6)),
+                FollowAction::Greet,
+                "follow #{i} is spaced out and must be greeted"
+            );
+        }
+    }
+
+    #[test]
+    fn threshold_starts_storm_and_suppresses_following() {
+        let mut s = storm(3, 10);
+        let t0 = Instant::now();
+        s.on_follow(t0);
+        s.on_follow(t0);
+        assert_eq!(s.on_follow(t0), FollowAction::StormStarted);
+        assert_eq!(s.on_follow(t0), FollowAction::Suppressed);
+        assert_eq!(s.on_follow(t0), FollowAction::Suppressed);
+        // StormStarted の 1 件 + Suppressed の 2 件
+        assert_eq!(s.tick(t0 + Duration::from_secs(1)), TickAction::Summary(3));
+        assert_eq!(s.tick(t0 + Duration::from_secs(2)), TickAction::Idle);
+    }
+
+    #[test]
+    fn storm_ends_once_rate_drops_below_threshold() {
+        let mut s = storm(2, 10);
+        let t0 = Instant::now();
+        s.on_follow(t0);
+        assert_eq!(s.on_follow(t0), FollowAction::StormStarted);
+        s.on_follow(t0 + Duration::from_secs(5));
+        assert_eq!(
+            s.tick(t0 + Duration::from_secs(20)),
+            TickAction::StormEnded(2)
+        );
+        // 収まった後は通常の挨拶に戻る
+        assert_eq!(
+            s.on_follow(t0 + Duration::from_secs(21)),
+            FollowAction::Greet
+        );
+    }
+
+    #[test]
+    fn zero_threshold_disables_detection() {
+        let mut s = storm(0, 10);
+        let t0 = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(s.on_follow(t0), FollowAction::Greet);
+        }
+    }
+
+    #[test]
+    fn summary_text_fills_count() {
+        assert_eq!(
+            summary_text(&FollowStormSettings::default().summary_template, 12),
+            "12人がフォローしました。"
+        );
+    }
+
+    #[test]
+    fn mitigated_flag_is_taken_once() {
+        let mut s = storm(3, 10);
+        s.set_mitigated(true);
+        assert!(s.take_mitigated());
+        assert!(!s.take_mitigated());
+    }
+}
@@ -0,0 +1,171 @@
+//! 初見さんと常連さんへの挨拶。
+//!
+//! Twitch は初めてチャンネルで発言した人のメッセージに `first-msg=1`、最近も来ている人に
+//! `returning-chatter=1` を付ける。これを見て `[greetings]` のテンプレートを読み上げる。
+//! 挨拶は配信ごとに 1 人 1 回で、挨拶済みの一覧はデータディレクトリのファイルに残すので
+//! 再起動しても同じ配信の中で挨拶し直さない。配信が変わると一覧を空にする。
+
+use crate::settings::GreetingSettings;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GreetingKind {
+    /// チャンネルで初めての発言。
+    First,
+    /// 常連さんのこの配信での最初の発言。
+    Returning,
+}
+
+/// IRC タグから挨拶の種類を決める。
+pub fn greeting_kind(tags: &BTreeMap<String, String>) -> Option<GreetingKind> {
+    let flag = |name: &str| tags.get(name).is_some_and(|v| v == "1");
+    if flag("first-msg") {
+        Some(GreetingKind::First)
+    } else if flag("returning-chatter") {
+        Some(GreetingKind::Returning)
+    } else {
+        None
+    }
+}
+
+/// `kind` の挨拶文。テンプレートが空なら挨拶しない。
+pub fn greeting_text(
+    settings: &GreetingSettings,
+    kind: GreetingKind,
+    user_name: &str,
+) -> Option<String> {
+    let template = match kind {
+        GreetingKind::First => &settings.first_template,
+        GreetingKind::Returning => &settings.returning_template,
+    };
+    (!template.is_empty()).then(|| template.replace("user_name", user_name))
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct GreetedState {
+    /// 配信の ID。配信を知る前は空。
+    stream: String,
+    /// 挨拶済みのユーザー ID。
+    greeted: BTreeSet<String>,
+}
+
+/// 挨拶済みの記録。IRC と EventSub のタスクで共有する。
+pub struct Greeter {
+    path: PathBuf,
+    state: Mutex<GreetedState>,
+}
+
+impl Greeter {
+    /// `path` から読む。無いか壊れていれば空から始める。
+    pub fn load(path: PathBuf) -> Self {
+        let state = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!("greetings: ignore broken {}: {}", path.display(), e);
+                GreetedState::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => GreetedState::default(),
+            Err(e) => {
+                warn!("greetings: failed to read {}: {}", path.display(), e);
+                GreetedState::default()
+            }
+        };
+        Self {
+            path,
+            state: Mutex::new(state),
+        }
+    }
+
+    fn save(&self, state: &GreetedState) {
+        let result = serde_json::to_string(state)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(std::fs::write(&self.path, text)?));
+        if let Err(e) = result {
+            warn!("greetings: failed to write {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// 配信が始まった（または起動時に配信中だった）。前と違う配信なら挨拶済みを空にする。
+    pub fn start_stream(&self, stream_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.stream == stream_id {
+            return;
+        }
+        *state = GreetedState {
+            stream: stream_id.to_string(),
+            greeted: BTreeSet::new(),
+        };
+        self.save(&state);
+    }
+
+    /// `user_id` をこの配信で挨拶済みにする。既に挨拶済みなら `false`。
+    pub fn mark(&self, user_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.greeted.insert(user_id.to_string()) {
+            return false;
+        }
+        self.save(&state);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn first_msg_wins_over_returning_chatter() {
+        assert_eq!(
+            greeting_kind(&tags(&[("first-msg", "1"), ("returning-chatter", "0")])),
+            Some(GreetingKind::First)
+        );
+        assert_eq!(
+            greeting_kind(&tags(&[("first-msg", "0"), ("returning-chatter", "1")])),
+            Some(GreetingKind::Returning)
+        );
+        assert_eq!(greeting_kind(&tags(&[("first-msg", "0")])), None);
+    }
+
+    #[test]
+    fn empty_template_disables_the_greeting() {
+        let settings = GreetingSettings {
+            returning_template: String::new(),
+            ..GreetingSettings::default()
+        };
+        assert_eq!(
+            greeting_text(&settings, GreetingKind::First, "alice"),
+            Some(String::from("初見さんいらっしゃい。alice さん"))
+        );
+        assert_eq!(
+            greeting_text(&settings, GreetingKind::Returning, "alice"),
+            None
+        );
+    }
+
+    #[test]
+    fn greeted_survives_restart_until_the_stream_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("greetings.json");
+        let greeter = Greeter::load(path.clone());
+        greeter.start_stream("100");
+        assert!(greeter.mark("1"));
+        assert!(!greeter.mark("1"));
+
+        let restarted = Greeter::load(path.clone());
+        restarted.start_stream("100");
+        assert!(!restarted.mark("1"));
+
+        restarted.start_stream("200");
+        assert!(restarted.mark("1"));
+    }
+}
@@ -0,0 +1,331 @@
+//! Helix API 呼び出しの共通窓口。
+//!
+//! `Store` と共有 HTTP クライアントを包み、401 ならトークンを更新して 1 度だけ
+//! やり直す。`Ratelimit-Remaining` / `Ratelimit-Reset` ヘッダを覚えておき、
+//! 残数が尽きていればリセットまで待ってから送る。429 もリセットまで待って再送する。
+
+use crate::api::{self, auth_headers, ValidatedToken, HTTP_CLIENT};
+use crate::settings::Settings;
+use crate::store::{Store, StoreError};
+use log::warn;
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+/// 429 を受けて待ち直す回数の上限。
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+/// 429 に `Ratelimit-Reset` が無いときの待ち時間。
+const DEFAULT_RATE_LIMIT_WAIT_SECS: u64 = 1;
+/// 時計ずれで極端に長く待たないための上限。Helix のバケットは 1 分で満ちる。
+const MAX_RATE_LIMIT_WAIT_SECS: u64 = 60;
+
+/// read-chat の各タスクで 1 つのクライアント（= 1 つのトークン）を共有するための型。
+pub type SharedHelix = Arc<Mutex<HelixClient>>;
+
+#[derive(Error, Debug)]
+pub enum HelixError {
+    #[error("user not found")]
+    UserNotFound,
+    #[error("helix returned {status}: {message}")]
+    Api { status: StatusCode, message: String },
+    #[error("helix rate limit still exceeded after {0} retries")]
+    RateLimited(u32),
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
+}
+
+impl HelixError {
+    /// Helix が返した HTTP ステータス。通信エラーなど応答が無い場合は `None`。
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Api { status, .. } => Some(*status),
+            Self::RateLimited(_) => Some(StatusCode::TOO_MANY_REQUESTS),
+            Self::RequestError(e) => e.status(),
+            Self::UserNotFound | Self::StoreError(_) => None,
+        }
+    }
+}
+
+/// Helix のエラー応答本文（`{"error": ..., "status": ..., "message": ...}`）。
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RateLimit {
+    remaining: u64,
+    /// バケットが満ちる時刻（Unix 秒）。
+    reset: u64,
+}
+
+impl RateLimit {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let get = |name: &str| headers.get(name)?.to_str().ok()?.parse::<u64>().ok();
+        Some(Self {
+            remaining: get("ratelimit-remaining")?,
+            reset: get("ratelimit-reset")?,
+        })
+    }
+
+    /// 残数が尽きているとき、次を送る前に待つべき時間。
+    fn wait_before_next(&self, now: u64) -> Option<Duration> {
+        if self.remaining > 0 {
+            return None;
+        }
+        let secs = self.reset.saturating_sub(now).min(MAX_RATE_LIMIT_WAIT_SECS);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// 429 を受けたときの待ち時間。リセット済みに見えても最低 1 秒は空ける。
+    fn wait_after_limited(limit: Option<Self>, now: u64) -> Duration {
+        let secs = match limit {
+            Some(l) => l
+                .reset
+                .saturating_sub(now)
+                .clamp(1, MAX_RATE_LIMIT_WAIT_SECS),
+            None => DEFAULT_RATE_LIMIT_WAIT_SECS,
+        };
+        Duration::from_secs(secs)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+pub struct HelixClient {
+    store: Store,
+    client_id: String,
+    client_secret: String,
+    rate_limit: Option<RateLimit>,
+}
+
+impl HelixClient {
+    pub fn new(store: Store, client_id: &str, client_secret: &str) -> Self {
+        Self {
+            store,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            rate_limit: None,
+        }
+    }
+
+    /// 設定のストアから `account` のトークンを開く。
+    pub fn from_settings(settings: &Settings, account: &str) -> Result<Self, std::io::Error> {
+        let cipher = crate::cipher::from_settings(settings)?;
+        let store = Store::new(&settings.db_dir, &settings.db_name, account, cipher)?;
+        Ok(Self::new(
+            store,
+            &settings.client_id,
+            &settings.client_secret,
+        ))
+    }
+
+    pub fn access_token(&self) -> &str {
+        self.store.access_token()
+    }
+
+    /// 別プロセス（`tcyb refresh-token` など）がストアを書き換えていれば読み直す。
+    pub fn reload_if_changed(&mut self) -> Result<bool, StoreError> {
+        Ok(self.store.reload_if_changed()?)
+    }
+
+    pub async fn refresh(&mut self) -> Result<(), StoreError> {
+        self.store
+            .update_tokens(&self.client_id, &self.client_secret)
+            .await
+    }
+
+    /// `/oauth2/validate` で有効性を確かめ、期限とスコープをストアへ記録する。
+    /// 失効していれば 1 度だけ更新して確かめ直す。
+    pub async fn validate(&mut self) -> Result<ValidatedToken, HelixError> {
+        let token = match api::validate_token(self.store.access_token()).await {
+            Err(e) if e.status() == Some(StatusCode::UNAUTHORIZED) => {
+                warn!("refresh token: validate returned 401");
+                self.refresh().await?;
+                api::validate_token(self.store.access_token()).await?
+            }
+            res => res?,
+        };
+        self.store
+            .set_validation(token.expires_in, token.scopes.clone(), unix_now())
+            .map_err(StoreError::from)?;
+        Ok(token)
+    }
+
+    /// 最後の validate から見た残り有効秒数。未確認か期限なしなら `None`。
+    pub fn remaining_secs(&self) -> Option<u64> {
+        self.store
+            .expires_at()
+            .map(|at| at.saturating_sub(unix_now()))
+    }
+
+    /// トークンの持ち主のユーザー ID。初回だけ validate で調べ、以降はアカウントごとに
+    /// ストアへ記録した値を使う。
+    pub async fn user_id(&mut self) -> Result<String, HelixError> {
+        if self.store.user_id().is_empty() {
+            let id = self
+                .validate()
+                .await?
+                .user_id
+                .ok_or(HelixError::UserNotFound)?;
+            self.store.set_user_id(id).map_err(StoreError::from)?;
+        }
+        Ok(self.store.user_id().to_string())
+    }
+
+    /// トークンの持ち主の login。IRC ではこの名前で NICK を送る。
+    pub async fn login(&mut self) -> Result<String, HelixError> {
+        self.validate().await?.login.ok_or(HelixError::UserNotFound)
+    }
+
+    /// 認証ヘッダを付けて送る。`build` は再送のたびに呼ばれる。
+    pub async fn send(
+        &mut self,
+        build: impl Fn(&reqwest::Client) -> RequestBuilder,
+    ) -> Result<Response, HelixError> {
+        self.reload_if_changed()?;
+        let mut refreshed = false;
+        let mut limited = 0;
+        loop {
+            if let Some(wait) = self.rate_limit.and_then(|l| l.wait_before_next(unix_now())) {
+                warn!(
+                    "helix rate limit exhausted, wait {}s for reset",
+                    wait.as_secs()
+                );
+                tokio::time::sleep(wait).await;
+            }
+            let res = build(&HTTP_CLIENT)
+                .headers(auth_headers(self.store.access_token(), &self.client_id))
+                .send()
+                .await?;
+            self.rate_limit = RateLimit::from_headers(res.headers());
+            match res.status() {
+                StatusCode::UNAUTHORIZED if !refreshed => {
+                    warn!("refresh token: helix returned 401");
+                    self.refresh().await?;
+                    refreshed = true;
+                }
+                StatusCode::TOO_MANY_REQUESTS if limited < MAX_RATE_LIMIT_RETRIES => {
+                    limited += 1;
+                    let wait = RateLimit::wait_after_limited(self.rate_limit, unix_now());
+                    warn!("helix rate limit exceeded, retry in {}s", wait.as_secs());
+                    tokio::time::sleep(wait).await;
+                }
+                StatusCode::TOO_MANY_REQUESTS => return Err(HelixError::RateLimited(limited)),
+                status if status.is_client_error() || status.is_server_error() => {
+                    let message = match res.json::<ErrorBody>().await {
+                        Ok(body) => body.message,
+                        Err(_) => String::from(status.canonical_reason().unwrap_or_default()),
+                    };
+                    return Err(HelixError::Api { status, message });
+                }
+                _ => return Ok(res),
+            }
+        }
+    }
+
+    pub async fn send_json<T: DeserializeOwned>(
+        &mut self,
+        build: impl Fn(&reqwest::Client) -> RequestBuilder,
+    ) -> Result<T, HelixError> {
+        Ok(self.send(build).await?.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(remaining: &str, reset: &str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert("Ratelimit-Remaining", remaining.parse().unwrap());
+        h.insert("Ratelimit-Reset", reset.parse().unwrap());
+        h
+    }
+
+    #[test]
+    fn rate_limit_parses_headers_case_insensitively() {
+        assert_eq!(
+            RateLimit::from_headers(&headers("799", "1700000000")),
+            Some(RateLimit {
+                remaining: 799,
+                reset: 1_700_000_000
+            })
+        );
+    }
+
+    #[test]
+    fn rate_limit_missing_or_broken_headers_is_none() {
+        assert_eq!(RateLimit::from_headers(&HeaderMap::new()), None);
+        assert_eq!(RateLimit::from_headers(&headers("x", "1")), None);
+    }
+
+    #[test]
+    fn no_wait_while_points_remain() {
+        let l = RateLimit {
+            remaining: 1,
+            reset: 200,
+        };
+        assert_eq!(l.wait_before_next(100), None);
+    }
+
+    #[test]
+    fn exhausted_bucket_waits_until_reset() {
+        let l = RateLimit {
+            remaining: 0,
+            reset: 110,
+        };
+        assert_eq!(l.wait_before_next(100), Some(Duration::from_secs(10)));
+        // リセット時刻を過ぎていれば待たない
+        assert_eq!(l.wait_before_next(120), None);
+    }
+
+    #[test]
+    fn wait_is_capped_against_clock_skew() {
+        let l = RateLimit {
+            remaining: 0,
+            reset: 100_000,
+        };
+        assert_eq!(
+            l.wait_before_next(0),
+            Some(Duration::from_secs(MAX_RATE_LIMIT_WAIT_SECS))
+        );
+    }
+
+    #[test]
+    fn limited_response_waits_at_least_a_second() {
+        let past = RateLimit {
+            remaining: 0,
+            reset: 50,
+        };
+        assert_eq!(
+            RateLimit::wait_after_limited(Some(past), 100),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            RateLimit::wait_after_limited(None, 100),
+            Duration::from_secs(DEFAULT_RATE_LIMIT_WAIT_SECS)
+        );
+    }
+
+    #[test]
+    fn error_status_comes_from_api_response() {
+        let err = HelixError::Api {
+            status: StatusCode::BAD_REQUEST,
+            message: String::from("already banned"),
+        };
+        assert_eq!(err.status(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(HelixError::UserNotFound.status(), None);
+    }
+}
@@ -2,6 +2,8 @@ use futures_util::{SinkExt, StreamExt};
 use lazy_static::lazy_static;
 use log::{info, warn};
 use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::process::Command;
 use tokio_tungstenite::{
@@ -10,9 +12,22 @@ use tokio_tungstenite::{
 use tracing::Instrument;
 use url::Url;
 
+use crate::chatlog::{ChatLog, Record, RecordKind, SpeechOutcome};
+use crate::control::{Connection, Control};
+use crate::greeting::{greeting_kind, greeting_text, Greeter};
+use crate::overlay::{FeedItem, Overlay};
+use crate::reload::LiveSettings;
+use crate::settings::Settings;
+use crate::speech::{speak, speak_as, SpeechEvent};
+use crate::stream::ReadingGate;
+use crate::timer::Outbox;
+use crate::voice::{roles_from_badges, voice_for, Role};
+
 const TRANSLATE_TIMEOUT_SECS: u64 = 10;
 const PING_INTERVAL_SECS: u64 = 60;
 const PING_SEND_TIMEOUT_SECS: u64 = 5;
+/// `[[timers]]` の周期を確かめる間隔。
+const TIMER_CHECK_SECS: u64 = 5;
 
 #[derive(Error, Debug)]
 pub enum ChatError {
@@ -24,24 +39,38 @@ pub enum ChatError {
     ConnectionError(#[from] tokio_tungstenite::tungstenite::Error),
 }
 
-#[allow(clippy::too_many_arguments)]
+/// IRC の受信処理に必要な値一式。接続ごとに yomiage が組み立てて渡す。
+/// 読み上げ先・経路・テンプレートは `live` から 1 通ごとに読む。
+pub struct ChatContext {
+    pub live: LiveSettings,
+    pub reading: Arc<ReadingGate>,
+    pub chat_log: Arc<ChatLog>,
+    pub outbox: Arc<Outbox>,
+    pub greeter: Arc<Greeter>,
+    pub control: Arc<Control>,
+    pub overlay: Arc<Overlay>,
+}
+
+/// `username` は `access_token` の持ち主の login。NICK と、自分の発言を読み飛ばす判定に使う。
 pub async fn read_chat_client_loop(
     url: Url,
     access_token: String,
     username: String,
     channel: String,
-    address: String,
-    operations: Vec<String>,
+    ctx: ChatContext,
     timeout_sec: u64,
-    translate_command: String,
 ) -> Result<(), ChatError> {
     let mut ws_stream = connect_and_authorize(&url, &access_token, &username, &channel).await?;
     crate::profiling::mark_ready(crate::profiling::Component::Irc);
+    let _connected = ctx.control.connected(Connection::Irc);
     let idle_timeout = std::time::Duration::from_secs(timeout_sec);
     let mut ping_interval =
         tokio::time::interval(std::time::Duration::from_secs(PING_INTERVAL_SECS));
     ping_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     ping_interval.tick().await;
+    let mut timer_interval =
+        tokio::time::interval(std::time::Duration::from_secs(TIMER_CHECK_SECS));
+    timer_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     let mut last_received = tokio::time::Instant::now();
     loop {
         let elapsed = last_received.elapsed();
@@ -56,14 +85,15 @@ pub async fn read_chat_client_loop(
                     Ok(Some(msg_res)) => {
                         last_received = tokio::time::Instant::now();
                         let msg = msg_res?;
+                        // 1 通の処理の間は同じ設定を使う（途中で読み直されても混ざらない）
+                        let settings = ctx.live.borrow().clone();
                         if let Err(e) = process_message(
                             &mut ws_stream,
                             msg,
-                            &address,
-                            &operations,
+                            &settings,
                             &username,
                             &channel,
-                            &translate_command,
+                            &ctx,
                         )
                         .await
                         {
@@ -76,6 +106,7 @@ pub async fn read_chat_client_loop(
                                 }
                                 MessageError::VstcError(e) => {
                                     warn!("vstc error {}: ignore it.", e);
+                                    ctx.control.record_error("speech", e);
                                 }
                             }
                         }
@@ -106,11 +137,56 @@ pub async fn read_chat_client_loop(
                     }
                 }
             }
+            _ = timer_interval.tick() => {
+                let settings = ctx.live.borrow().clone();
+                run_timers(&mut ws_stream, &settings, &channel, &ctx).await?;
+            }
+        }
+    }
+}
+
+/// 周期の来た `[[timers]]` を投稿する。送信数の制限に当たった回は見送る。
+async fn run_timers(
+    ws_stream: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+    settings: &Settings,
+    channel: &str,
+    ctx: &ChatContext,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    for timer in ctx.outbox.due(&settings.timers, std::time::Instant::now()) {
+        if let Err(wait) = ctx.outbox.try_send(std::time::Instant::now()) {
+            warn!(
+                "timer: outbound limit reached, skip {:?} (free in {}s)",
+                timer.message,
+                wait.as_secs()
+            );
+            continue;
+        }
+        ws_stream
+            .send(Message::Text(format!(
+                "PRIVMSG #{channel} :{}",
+                timer.message
+            )))
+            .await?;
+        info!("timer: {}", timer.message);
+        if timer.speak && !ctx.reading.is_muted() {
+            let spoken = ctx
+                .control
+                .speak(speak(
+                    &settings.speech_address,
+                    &settings.chains(SpeechEvent::Chat),
+                    timer.message.clone(),
+                ))
+                .await;
+            if let Err(e) = spoken {
+                warn!("timer: vstc error {}: ignore it.", e);
+                ctx.control.record_error("speech", e);
+            }
         }
     }
+    Ok(())
 }
 
-async fn connect_and_authorize(
+pub async fn connect_and_authorize(
     url: &Url,
     access_token: &str,
     username: &str,
@@ -124,6 +200,12 @@ async fn connect_and_authorize(
         .await?;
     info!("authorizing...");
     async {
+        // tags は発言者の情報、commands は ROOMSTATE / USERSTATE / NOTICE を受け取るのに要る
+        ws_stream
+            .send(Message::Text(String::from(
+                "CAP REQ :twitch.tv/tags twitch.tv/commands",
+            )))
+            .await?;
         ws_stream
             .send(Message::Text(format!("PASS oauth:{}", access_token)))
             .await?;
@@ -133,9 +215,6 @@ async fn connect_and_authorize(
         ws_stream
             .send(Message::Text(format!("JOIN #{}", channel)))
             .await?;
-        ws_stream
-            .send(Message::Text(String::from("CAP REQ :twitch.tv/tags")))
-            .await?;
         Ok::<(), tokio_tungstenite::tungstenite::Error>(())
     }
     .instrument(tracing::info_span!("irc_auth"))
@@ -153,80 +232,174 @@ enum MessageError {
     ConnectionError(#[from] tokio_tungstenite::tungstenite::Error),
 }
 
+/// チャット 1 件の記録の下地。読み上げと翻訳の結果は `on_chat` が埋める。
+fn chat_record(irc_message: &IrcMessage) -> Record {
+    Record {
+        user_id: irc_message.tags.get("user-id").cloned(),
+        user_login: irc_message.user.clone(),
+        user_name: irc_message.tags.get("display-name").cloned(),
+        text: irc_message.chat_msg.clone(),
+        tags: irc_message.tags.clone(),
+        ..Record::new(RecordKind::Chat)
+    }
+}
+
+/// 初見さん・常連さんのこの配信で最初の発言なら、発言より先に挨拶を読み上げる。
+/// 読み上げを止めている間も挨拶済みにはする（後から遅れて挨拶しない）。
+async fn greet(irc_message: &IrcMessage, settings: &Settings, ctx: &ChatContext) {
+    let Some(kind) = greeting_kind(&irc_message.tags) else {
+        return;
+    };
+    let Some(user_id) = irc_message.tags.get("user-id") else {
+        return;
+    };
+    let user_name = irc_message
+        .tags
+        .get("display-name")
+        .filter(|n| !n.is_empty())
+        .or(irc_message.user.as_ref())
+        .cloned()
+        .unwrap_or_default();
+    let Some(text) = greeting_text(&settings.greetings, kind, &user_name) else {
+        return;
+    };
+    if !ctx.greeter.mark(user_id) {
+        return;
+    }
+    info!("greet {:?} ({:?})", user_name, kind);
+    if ctx.reading.is_muted() {
+        return;
+    }
+    let spoken = ctx
+        .control
+        .speak(speak(
+            &settings.speech_address,
+            &settings.chains(SpeechEvent::Greeting),
+            text,
+        ))
+        .await;
+    if let Err(e) = spoken {
+        warn!("greeting: vstc error {}: ignore it.", e);
+        ctx.control.record_error("speech", e);
+    }
+}
+
+/// 読み上げ、翻訳して返信する。結果は `record` に残す。
+async fn on_chat(
+    ws_stream: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+    irc_message: &IrcMessage,
+    settings: &Settings,
+    channel: &str,
+    ctx: &ChatContext,
+    record: &mut Record,
+) -> Result<(), MessageError> {
+    let chat_msg = irc_message.chat_msg.clone().unwrap_or_default();
+    let user = irc_message.user.clone().unwrap_or_default();
+    info!(
+        "{:?} says {:?} in #{:?}",
+        user.as_str(),
+        chat_msg.as_str(),
+        irc_message.channel.as_deref().unwrap_or_default(),
+    );
+    if ctx.reading.is_muted() {
+        info!("reading is muted: not speaking");
+        record.speech = Some(SpeechOutcome::Muted);
+    } else if ctx.control.is_user_muted(&user) {
+        info!("{:?} is muted: not speaking", user.as_str());
+        record.speech = Some(SpeechOutcome::Skipped);
+    } else {
+        let voice = voice_for(&settings.voices, &user, &irc_message.roles);
+        let spoken = ctx
+            .control
+            .speak(speak_as(
+                &settings.speech_address,
+                &settings.chains(SpeechEvent::Chat),
+                chat_msg.clone(),
+                voice.as_ref(),
+            ))
+            .await;
+        record.speech = Some(SpeechOutcome::from_result(&spoken));
+        spoken?;
+    }
+    let msg_id = irc_message.msg_id.clone().unwrap_or_default();
+    let (cleaned, emotes) = split_message_emotes(&chat_msg, &irc_message.emote_ranges);
+    let emote_suffix = emotes.join(" ");
+
+    if cleaned.is_empty() {
+        // emote のみのメッセージ: 翻訳をスキップし emote だけ返信する。
+        if !emote_suffix.is_empty() {
+            send_reply(ws_stream, &msg_id, channel, &emote_suffix, &ctx.outbox).await;
+        }
+        return Ok(());
+    }
+
+    let translate_fut = Command::new(&settings.translate_command)
+        .args([cleaned.as_str()])
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(
+        std::time::Duration::from_secs(TRANSLATE_TIMEOUT_SECS),
+        translate_fut,
+    )
+    .await
+    {
+        Ok(Ok(output)) => {
+            let stdout = match std::str::from_utf8(&output.stdout) {
+                Ok(val) => val,
+                Err(err) => {
+                    warn!("{err}");
+                    ""
+                }
+            };
+            info!("{stdout}");
+            let translated = stdout.trim();
+            if !translated.is_empty() {
+                record.translation = Some(translated.to_string());
+            }
+            if let Some(body) = translated_reply_body(stdout, &emote_suffix) {
+                send_reply(ws_stream, &msg_id, channel, &body, &ctx.outbox).await;
+            }
+        }
+        Ok(Err(err)) => {
+            warn!("{err}");
+        }
+        Err(_) => {
+            warn!(
+                "translate command timed out after {}s, killed child",
+                TRANSLATE_TIMEOUT_SECS
+            );
+        }
+    }
+    Ok(())
+}
+
 async fn process_message(
     ws_stream: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
     msg: Message,
-    address: &str,
-    operations: &[String],
+    settings: &Settings,
     username: &str,
     channel: &str,
-    translate_command: &str,
+    ctx: &ChatContext,
 ) -> Result<(), MessageError> {
     if msg.is_text() || msg.is_binary() {
         let msg_str = msg.into_text()?;
         let irc_message = parse_message(&msg_str);
         match irc_message.kind {
             IrcMessageKind::Chat => {
-                let chat_msg = irc_message.chat_msg.unwrap_or_default();
-                let user = irc_message.user.unwrap_or_default();
-                if user != username {
-                    info!(
-                        "{:?} says {:?} in #{:?}",
-                        user.as_str(),
-                        chat_msg.as_str(),
-                        irc_message.channel.unwrap_or_default().as_str(),
-                    );
-                    send_chat_message_to_speak(chat_msg.as_str(), address, operations).await?;
-                    let msg_id = irc_message.msg_id.unwrap_or_default();
-                    let (cleaned, emotes) =
-                        split_message_emotes(&chat_msg, &irc_message.emote_ranges);
-                    let emote_suffix = emotes.join(" ");
-
-                    if cleaned.is_empty() {
-                        // emote のみのメッセージ: 翻訳をスキップし emote だけ返信する。
-                        if !emote_suffix.is_empty() {
-                            send_reply(ws_stream, &msg_id, channel, &emote_suffix).await;
-                        }
-                        return Ok(());
-                    }
-
-                    let translate_fut = Command::new(translate_command)
-                        .args([cleaned.as_str()])
-                        .kill_on_drop(true)
-                        .output();
-                    match tokio::time::timeout(
-                        std::time::Duration::from_secs(TRANSLATE_TIMEOUT_SECS),
-                        translate_fut,
-                    )
-                    .await
-                    {
-                        Ok(Ok(output)) => {
-                            let stdout = match std::str::from_utf8(&output.stdout) {
-                                Ok(val) => val,
-                                Err(err) => {
-                                    warn!("{err}");
-                                    ""
-                                }
-                            };
-                            info!("{stdout}");
-                            if let Some(body) = translated_reply_body(stdout, &emote_suffix) {
-                                send_reply(ws_stream, &msg_id, channel, &body).await;
-                            }
-                        }
-                        Ok(Err(err)) => {
-                            warn!("{err}");
-                        }
-                        Err(_) => {
-                            warn!(
-                                "translate command timed out after {}s, killed child",
-                                TRANSLATE_TIMEOUT_SECS
-                            );
-                        }
-                    }
-                    Ok(())
-                } else {
-                    Ok(())
+                if irc_message.user.as_deref() == Some(username) {
+                    return Ok(());
                 }
+                ctx.outbox.on_chat();
+                greet(&irc_message, settings, ctx).await;
+                let mut record = chat_record(&irc_message);
+                ctx.overlay.publish(&FeedItem::chat(&record));
+                let result =
+                    on_chat(ws_stream, &irc_message, settings, channel, ctx, &mut record).await;
+                if let Some(translation) = FeedItem::translation(&record) {
+                    ctx.overlay.publish(&translation);
+                }
+                ctx.chat_log.append(&record);
+                result
             }
             IrcMessageKind::LoginFailed => Err(MessageError::LoginFailed),
             IrcMessageKind::Ping => {
@@ -246,21 +419,18 @@ async fn process_message(
     }
 }
 
-async fn send_chat_message_to_speak(
-    chat_msg: &str,
-    uri: &str,
-    operations: &[String],
-) -> Result<(), vstc::VstcError> {
-    vstc::process_command(uri, operations, chat_msg.to_string(), None, None, None).await?;
-    Ok(())
-}
-
 async fn send_reply(
     ws_stream: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
     msg_id: &str,
     channel: &str,
     body: &str,
+    outbox: &Outbox,
 ) {
+    // 送信数の制限に当たったら枠が空くまで待つ
+    while let Err(wait) = outbox.try_send(std::time::Instant::now()) {
+        warn!("outbound limit reached, wait {}ms", wait.as_millis());
+        tokio::time::sleep(wait).await;
+    }
     let reply = format!("@reply-parent-msg-id={msg_id} PRIVMSG #{channel} :{body}");
     match ws_stream
         .send(Message::Text(String::from(reply.as_str())))
@@ -355,6 +525,8 @@ struct IrcMessage {
     user: Option<String>,
     channel: Option<String>,
     emote_ranges: Vec<(usize, usize)>,
+    roles: Vec<Role>,
+    tags: BTreeMap<String, String>,
 }
 
 fn find_tag<'a>(tags: &'a str, name: &str) -> Option<&'a str> {
@@ -364,6 +536,13 @@ fn find_tag<'a>(tags: &'a str, name: &str) -> Option<&'a str> {
     })
 }
 
+fn parse_tags(tags: &str) -> BTreeMap<String, String> {
+    tags.split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
 fn parse_message(msg_str: &str) -> IrcMessage {
     lazy_static! {
         static ref CHAT_MSG_PTN: Regex = Regex::new(
@@ -386,6 +565,8 @@ fn parse_message(msg_str: &str) -> IrcMessage {
                 channel: Some(caps["channel"].into()),
                 user: Some(caps["user"].into()),
                 emote_ranges,
+                roles: roles_from_badges(find_tag(tags, "badges").unwrap_or_default()),
+                tags: parse_tags(tags),
             };
         }
     } else if LOGIN_FAILED_PTN.is_match(msg_str) {
@@ -445,6 +626,27 @@ mod tests {
         assert_eq!(find_tag("emotes=;id=abc", "emotes"), Some(""));
     }
 
+    #[test]
+    fn parse_message_extracts_roles_from_badges() {
+        let message = parse_message(
+            "@badges=moderator/1,subscriber/12;emotes=;id=abc :u!u@u.tmi.twitch.tv PRIVMSG #chan :hi",
+        );
+        assert_eq!(message.roles, vec![Role::Moderator, Role::Subscriber]);
+    }
+
+    #[test]
+    fn chat_record_takes_user_fields_from_tags() {
+        let message = parse_message(
+            "@display-name=Alice;id=abc;user-id=42 :alice!alice@alice.tmi.twitch.tv PRIVMSG #chan :hi",
+        );
+        let record = chat_record(&message);
+        assert_eq!(record.user_id.as_deref(), Some("42"));
+        assert_eq!(record.user_login.as_deref(), Some("alice"));
+        assert_eq!(record.user_name.as_deref(), Some("Alice"));
+        assert_eq!(record.text.as_deref(), Some("hi"));
+        assert_eq!(record.tags["id"], "abc");
+    }
+
     #[test]
     fn parse_message_extracts_emote_ranges() {
         let message = parse_message(
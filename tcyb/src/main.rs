@@ -2,15 +2,33 @@ mod api;
 mod auth;
 mod channel;
 mod chat;
+mod chatlog;
+mod cipher;
+mod configcheck;
+mod control;
 mod eventsub;
+mod followstorm;
+mod greeting;
+mod helix;
 mod irc;
+mod output;
+mod overlay;
 mod paths;
 mod profiling;
+mod reload;
+mod say;
 mod settings;
+mod speech;
 mod store;
+mod stream;
+mod subscription;
+mod timer;
+mod token;
+mod voice;
 mod yomiage;
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use helix::HelixClient;
 use settings::Settings;
 use std::path::PathBuf;
 
@@ -25,12 +43,159 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     ReadChat {},
-    AuthCode {},
-    BanBots {},
-    RefreshToken {},
-    ShowChatters {},
-    ShowUser { username: String },
-    ShowFollowings { username: String },
+    AuthCode {
+        /// トークンを保存するアカウント名
+        #[arg(long, default_value = store::DEFAULT_ACCOUNT)]
+        account: String,
+    },
+    /// ブラウザの無い環境向けに Device Code Grant で認可する
+    AuthDevice {
+        /// トークンを保存するアカウント名
+        #[arg(long, default_value = store::DEFAULT_ACCOUNT)]
+        account: String,
+    },
+    /// 既知のボットを一覧から BAN する
+    BanBots {
+        /// BAN せずに対象と件数だけを表示する
+        #[arg(long)]
+        dry_run: bool,
+        /// リモートの一覧の代わりにこのファイルを使う（API の応答 JSON・名前の配列・1 行 1 名）
+        #[arg(long, value_name = "PATH")]
+        from_file: Option<PathBuf>,
+        /// 既知の無害なボットの一覧をリモートの代わりにこのファイルから読む（名前の JSON 配列・1 行 1 名）
+        #[arg(long, value_name = "PATH")]
+        allowlist_file: Option<PathBuf>,
+        /// BAN 理由（既定は設定の ban_bots.reason）
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    RefreshToken {
+        /// 更新するアカウント名
+        #[arg(long, default_value = store::DEFAULT_ACCOUNT)]
+        account: String,
+    },
+    /// bot としてチャットに投稿する（accounts.chat のトークンを使う）
+    Say {
+        text: String,
+        /// このメッセージ ID への返信にする
+        #[arg(long, value_name = "MSG_ID")]
+        reply_to: Option<String>,
+        /// `/me` として送る（IRC 経由）
+        #[arg(long)]
+        me: bool,
+    },
+    /// チャット参加者の一覧
+    ShowChatters {
+        /// 出力形式
+        #[arg(long, value_enum, default_value_t)]
+        format: output::OutputFormat,
+        /// この秒数ごとに取り直し、入退室の差分を出し続ける
+        #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        watch: Option<u64>,
+        /// 取得したスナップショットを JSON Lines で追記するファイル
+        #[arg(long, value_name = "PATH")]
+        snapshot_file: Option<PathBuf>,
+    },
+    /// ユーザーの情報（accounts.lookup のトークンを使う）
+    ShowUser {
+        username: String,
+    },
+    /// accounts.lookup のアカウントがフォロー中のチャンネルの一覧（新しい順）
+    ShowFollowings {
+        /// 出力形式
+        #[arg(long, value_enum, default_value_t)]
+        format: output::OutputFormat,
+        /// この件数までで打ち切る
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+        limit: Option<u64>,
+    },
+    /// EventSub 購読の確認・削除
+    Eventsub {
+        #[command(subcommand)]
+        command: EventsubCommands,
+    },
+    /// 保存済みトークンの確認
+    Token {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
+    /// read-chat が記録したチャットとイベントの検索
+    Logs {
+        #[command(subcommand)]
+        command: LogsCommands,
+    },
+    /// 設定ファイルの検査
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// トークンストアの暗号化・復号
+    Store {
+        #[command(subcommand)]
+        command: StoreCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// operations / translate_command / listen_address / テンプレートなどをキーごとに検査する
+    Check {},
+}
+
+#[derive(Subcommand)]
+enum LogsCommands {
+    /// 条件に合う記録を古い順に表示する（条件はすべて AND）
+    Search {
+        /// ログイン名か表示名
+        #[arg(long)]
+        user: Option<String>,
+        /// この日時以降（YYYY-MM-DD か RFC 3339）
+        #[arg(long, value_parser = chatlog::parse_since)]
+        since: Option<chrono::DateTime<chrono::FixedOffset>>,
+        /// 本文か翻訳に含まれる文字列
+        #[arg(long)]
+        text: Option<String>,
+        /// 1 行 1 件の JSON で出力する
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum StoreCommands {
+    /// 平文のトークンストアをパスフレーズで暗号化する
+    Encrypt {},
+    /// 暗号化したトークンストアを平文に戻す
+    Decrypt {},
+}
+
+#[derive(Subcommand)]
+enum TokenCommands {
+    /// login / user id / スコープ / 残り有効期間を表示し、足りないスコープを警告する
+    Status {
+        /// 確認するアカウント名
+        #[arg(long, default_value = store::DEFAULT_ACCOUNT)]
+        account: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum EventsubCommands {
+    /// 登録済みの購読を type / status / transport / cost 付きで一覧する
+    List {},
+    /// 購読を削除する
+    Delete {
+        #[arg(
+            required_unless_present = "all_disabled",
+            conflicts_with = "all_disabled"
+        )]
+        id: Option<String>,
+        /// status が enabled 以外の購読をすべて削除する
+        #[arg(long)]
+        all_disabled: bool,
+    },
+    /// 購読コストの合計と上限、type 別の内訳を表示する
+    Cost {},
 }
 
 #[tokio::main]
@@ -39,6 +204,11 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
     let app_paths = paths::app_paths()?;
 
+    let config_source = settings::ConfigSource {
+        config_file: app_paths.config_file.clone(),
+        cli_config: args.config.clone(),
+        default_db_dir: app_paths.db_dir.clone(),
+    };
     let settings: Settings = {
         let _span = tracing::info_span!("config_build").entered();
         if !app_paths.config_file.exists() && args.config.is_none() {
@@ -50,11 +220,7 @@ async fn main() -> Result<()> {
             println!("client_id / client_secret などを記入してから再実行してください。");
             return Ok(());
         }
-        settings::load(
-            &app_paths.config_file,
-            args.config.as_deref(),
-            &app_paths.db_dir,
-        )?
+        config_source.load()?
     };
 
     {
@@ -68,73 +234,148 @@ async fn main() -> Result<()> {
     match &args.command {
         Some(Commands::ReadChat {}) => {
             tokio::select! {
-                res = yomiage::yomiage(&settings) => res?,
+                res = yomiage::yomiage(&settings, config_source) => res?,
                 sig = tokio::signal::ctrl_c() => {
                     sig?;
                     log::warn!("Ctrl+C received, shutting down");
                 }
             }
         }
-        Some(Commands::AuthCode {}) => {
-            auth::auth_code_grant(
-                &settings.listen_address,
-                &settings.db_dir,
-                &settings.db_name,
-                &settings.client_id,
-                &settings.client_secret,
-            )
-            .await?;
-        }
-        Some(Commands::BanBots {}) => {
-            channel::ban_bots(
-                &settings.db_dir,
-                &settings.db_name,
-                &settings.username,
-                &settings.client_id,
-            )
-            .await?;
-        }
-        Some(Commands::RefreshToken {}) => {
-            auth::refresh_token_grant(
-                &settings.db_dir,
-                &settings.db_name,
-                &settings.client_id,
-                &settings.client_secret,
-            )
-            .await?;
-        }
-        Some(Commands::ShowChatters {}) => {
-            chat::chatters(
-                &settings.db_dir,
-                &settings.db_name,
-                &settings.channel,
-                &settings.username,
-                &settings.client_id,
-                &settings.client_secret,
-            )
-            .await?;
+        Some(Commands::AuthCode { account }) => {
+            auth::auth_code_grant(&settings, account, cipher::from_settings(&settings)?).await?;
+        }
+        Some(Commands::AuthDevice { account }) => {
+            auth::device_code_grant(&settings, account, cipher::from_settings(&settings)?).await?;
+        }
+        Some(Commands::BanBots {
+            dry_run,
+            from_file,
+            allowlist_file,
+            reason,
+        }) => {
+            let mut helix = HelixClient::from_settings(&settings, &settings.accounts.moderation)?;
+            let options = channel::BanBotsOptions {
+                dry_run: *dry_run,
+                from_file: from_file.clone(),
+                allowlist_file: allowlist_file.clone(),
+                reason: reason.clone(),
+            };
+            channel::ban_bots(&mut helix, &settings.channel, &settings.ban_bots, &options).await?;
+        }
+        Some(Commands::RefreshToken { account }) => {
+            auth::refresh_token_grant(&settings, account, cipher::from_settings(&settings)?)
+                .await?;
+        }
+        Some(Commands::ShowChatters {
+            format,
+            watch,
+            snapshot_file,
+        }) => {
+            let mut helix = HelixClient::from_settings(&settings, &settings.accounts.moderation)?;
+            let options = chat::ChattersOptions {
+                format: *format,
+                watch: *watch,
+                snapshot_file: snapshot_file.clone(),
+            };
+            chat::chatters(&mut helix, &settings.channel, &settings.username, &options).await?;
+        }
+        Some(Commands::Say { text, reply_to, me }) => {
+            let mut helix = HelixClient::from_settings(&settings, &settings.accounts.chat)?;
+            let options = say::SayOptions {
+                reply_to: reply_to.clone(),
+                me: *me,
+            };
+            say::say(&mut helix, &settings, text, &options).await?;
         }
         Some(Commands::ShowUser { username }) => {
-            chat::show_user_info(
-                &settings.db_dir,
-                &settings.db_name,
-                username,
-                &settings.client_id,
-                &settings.client_secret,
-            )
-            .await?;
-        }
-        Some(Commands::ShowFollowings { username }) => {
-            channel::show_following_info(
-                &settings.db_dir,
-                &settings.db_name,
-                username,
-                &settings.client_id,
-                &settings.client_secret,
-            )
-            .await?;
+            let mut helix = HelixClient::from_settings(&settings, &settings.accounts.lookup)?;
+            chat::show_user_info(&mut helix, username).await?;
+        }
+        Some(Commands::ShowFollowings { format, limit }) => {
+            let mut helix = HelixClient::from_settings(&settings, &settings.accounts.lookup)?;
+            let options = channel::FollowingsOptions {
+                format: *format,
+                limit: limit.map(|l| l as usize),
+            };
+            channel::show_following_info(&mut helix, &options).await?;
+        }
+        Some(Commands::Eventsub { command }) => eventsub_command(command, &settings).await?,
+        Some(Commands::Store { command }) => store_command(command, &settings)?,
+        Some(Commands::Logs { command }) => logs_command(command, &settings)?,
+        Some(Commands::Config { command }) => config_command(command, &settings)?,
+        Some(Commands::Token {
+            command: TokenCommands::Status { account },
+        }) => {
+            let mut helix = HelixClient::from_settings(&settings, account)?;
+            token::status(&mut helix, &settings, account).await?;
         }
         None => {}
     }
     Ok(())
 }
+
+fn config_command(command: &ConfigCommands, settings: &Settings) -> Result<()> {
+    match command {
+        ConfigCommands::Check {} => {
+            let findings = configcheck::check(settings, std::env::var_os("PATH"));
+            let errors = configcheck::print_report(&findings);
+            if errors > 0 {
+                anyhow::bail!("config check found {} errors", errors);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn eventsub_command(command: &EventsubCommands, settings: &Settings) -> Result<()> {
+    let mut helix = HelixClient::from_settings(settings, &settings.accounts.events)?;
+    match command {
+        EventsubCommands::List {} => subscription::list(&mut helix).await?,
+        EventsubCommands::Delete { id, all_disabled } => {
+            subscription::delete(&mut helix, id.as_deref(), *all_disabled).await?;
+        }
+        EventsubCommands::Cost {} => subscription::cost(&mut helix).await?,
+    }
+    Ok(())
+}
+
+fn logs_command(command: &LogsCommands, settings: &Settings) -> Result<()> {
+    match command {
+        LogsCommands::Search {
+            user,
+            since,
+            text,
+            json,
+        } => {
+            let query = chatlog::Query {
+                user: user.clone(),
+                since: *since,
+                text: text.clone(),
+            };
+            for record in chatlog::search(&settings.chat_log_dir(), &query)? {
+                if *json {
+                    println!("{}", serde_json::to_string(&record)?);
+                } else {
+                    println!("{}", chatlog::format_record(&record));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn store_command(command: &StoreCommands, settings: &Settings) -> Result<()> {
+    match command {
+        StoreCommands::Encrypt {} => {
+            let cipher = cipher::Cipher::new(&cipher::passphrase(true)?);
+            store::reseal(&settings.db_dir, &settings.db_name, None, Some(&cipher))?;
+            println!("トークンストアを暗号化しました。設定に `encrypt_store = true` を追加してください。");
+        }
+        StoreCommands::Decrypt {} => {
+            let cipher = cipher::Cipher::new(&cipher::passphrase(false)?);
+            store::reseal(&settings.db_dir, &settings.db_name, Some(&cipher), None)?;
+            println!("トークンストアを復号しました。設定の `encrypt_store` を外してください。");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,23 @@
+//! 一覧系サブコマンドの出力形式。
+
+use clap::ValueEnum;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// カンマ区切り（既定。従来の出力と同じ）
+    #[default]
+    Csv,
+    /// JSON（`--watch` では 1 行 1 件の JSON Lines）
+    Json,
+    /// 桁を揃えた表
+    Table,
+}
+
+/// CSV の 1 フィールド。区切りや引用符を含むときだけ引用符で囲む。
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,313 @@
+//! OBS のブラウザソース向けのチャット表示（`[overlay]`）。
+//!
+//! `GET /` で表示用の HTML を返し、`GET /ws` の WebSocket で受け取ったチャット・翻訳・
+//! イベントを 1 件 1 つの JSON テキストとして流す。流すのは接続してから届いたものだけで、
+//! 過去の分は送らない。表示するだけの口なので認証は無く、bind できるのはループバックの
+//! アドレスだけ。
+
+use crate::chatlog::{Record, RecordKind};
+use crate::settings::OverlaySettings;
+use anyhow::Context;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::{Html, Response},
+    routing::get,
+    Router,
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+const OVERLAY_HTML: &str = include_str!("overlay.html");
+
+/// 表示の遅いクライアントのために溜めておく件数。溢れた分はそのクライアントに届かない。
+const FEED_CAPACITY: usize = 256;
+
+/// エモートの位置。Twitch の `emotes` タグと同じく、本文の文字（コードポイント）単位で
+/// `start` から `end` まで（`end` を含む）。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Emote {
+    pub id: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// WebSocket で流す 1 件。`type` で種類を見分ける。
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedItem {
+    Chat {
+        /// メッセージ ID。後から届く `translation` と突き合わせる。
+        id: Option<String>,
+        time: String,
+        user_login: Option<String>,
+        user_name: Option<String>,
+        /// `#RRGGBB`。ユーザーが色を決めていなければ `None`。
+        color: Option<String>,
+        text: String,
+        emotes: Vec<Emote>,
+    },
+    Translation {
+        id: String,
+        text: String,
+    },
+    /// フォロー・レイド・配信の開始と終了。
+    Event {
+        kind: RecordKind,
+        time: String,
+        user_name: Option<String>,
+        text: Option<String>,
+    },
+}
+
+/// `emotes` タグ（`25:0-4,12-16/1902:6-10`）を位置の順に読む。読めない部分は飛ばす。
+fn parse_emotes(tag: &str) -> Vec<Emote> {
+    let mut emotes: Vec<Emote> = tag
+        .split('/')
+        .filter_map(|emote| emote.split_once(':'))
+        .flat_map(|(id, ranges)| {
+            ranges.split(',').filter_map(move |range| {
+                let (start, end) = range.split_once('-')?;
+                Some(Emote {
+                    id: id.to_string(),
+                    start: start.parse().ok()?,
+                    end: end.parse().ok()?,
+                })
+            })
+        })
+        .collect();
+    emotes.sort_by_key(|e| e.start);
+    emotes
+}
+
+impl FeedItem {
+    /// チャットの記録から。翻訳は `translation` で別に流す。
+    pub fn chat(record: &Record) -> Self {
+        let tag = |name: &str| record.tags.get(name).filter(|v| !v.is_empty()).cloned();
+        Self::Chat {
+            id: tag("id"),
+            time: record.time.clone(),
+            user_login: record.user_login.clone(),
+            user_name: record.user_name.clone(),
+            color: tag("color"),
+            text: record.text.clone().unwrap_or_default(),
+            emotes: tag("emotes").map(|t| parse_emotes(&t)).unwrap_or_default(),
+        }
+    }
+
+    /// チャットの記録に翻訳が付いていれば、その翻訳。
+    pub fn translation(record: &Record) -> Option<Self> {
+        Some(Self::Translation {
+            id: record.tags.get("id")?.clone(),
+            text: record.translation.clone()?,
+        })
+    }
+
+    /// EventSub の記録から。
+    pub fn event(record: &Record) -> Self {
+        Self::Event {
+            kind: record.kind,
+            time: record.time.clone(),
+            user_name: record.user_name.clone(),
+            text: record.text.clone(),
+        }
+    }
+}
+
+/// 表示へ流す口。IRC と EventSub のタスクで共有する。
+pub struct Overlay {
+    feed: broadcast::Sender<String>,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Self {
+            feed: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+}
+
+impl Overlay {
+    /// 接続中の表示すべてへ流す。誰も繋いでいなければ捨てる。
+    pub fn publish(&self, item: &FeedItem) {
+        match serde_json::to_string(item) {
+            Ok(json) => {
+                let _ = self.feed.send(json);
+            }
+            Err(e) => warn!("overlay: failed to encode {:?}: {}", item, e),
+        }
+    }
+}
+
+async fn index() -> Html<&'static str> {
+    Html(OVERLAY_HTML)
+}
+
+async fn feed(ws: WebSocketUpgrade, State(overlay): State<Arc<Overlay>>) -> Response {
+    // 応答を返す前に受け取り始めるので、接続が済んだ直後の 1 件も取りこぼさない
+    let items = overlay.feed.subscribe();
+    ws.on_upgrade(move |socket| forward(socket, items))
+}
+
+async fn forward(mut socket: WebSocket, mut items: broadcast::Receiver<String>) {
+    loop {
+        tokio::select! {
+            item = items.recv() => match item {
+                Ok(json) => {
+                    if socket.send(Message::Text(json.into())).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => warn!("overlay: client lagged, dropped {} items", n),
+                Err(RecvError::Closed) => return,
+            },
+            // クライアントからは何も受け付けない。切断だけを見る
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+fn router(overlay: Arc<Overlay>) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/ws", get(feed))
+        .with_state(overlay)
+}
+
+async fn serve(listener: TcpListener, overlay: Arc<Overlay>) {
+    if let Err(e) = axum::serve(listener, router(overlay)).await {
+        warn!("overlay stopped: {}", e);
+    }
+}
+
+/// `listen_address` が設定されていれば表示を開く。
+pub async fn start(
+    settings: &OverlaySettings,
+    overlay: Arc<Overlay>,
+) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let Some(address) = &settings.listen_address else {
+        return Ok(None);
+    };
+    let addr = crate::control::loopback_addr("overlay.listen_address", address)?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("overlay を {addr} で開けません"))?;
+    info!("overlay listening on http://{}", addr);
+    Ok(Some(tokio::spawn(serve(listener, overlay))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::collections::BTreeMap;
+
+    fn chat(tags: &[(&str, &str)], text: &str) -> Record {
+        Record {
+            user_login: Some(String::from("alice")),
+            user_name: Some(String::from("Alice")),
+            text: Some(text.to_string()),
+            tags: tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            ..Record::new(RecordKind::Chat)
+        }
+    }
+
+    #[test]
+    fn emotes_are_sorted_by_position() {
+        assert_eq!(
+            parse_emotes("25:6-10,18-22/1902:0-4/broken"),
+            vec![
+                Emote {
+                    id: String::from("1902"),
+                    start: 0,
+                    end: 4
+                },
+                Emote {
+                    id: String::from("25"),
+                    start: 6,
+                    end: 10
+                },
+                Emote {
+                    id: String::from("25"),
+                    start: 18,
+                    end: 22
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn chat_takes_color_and_emotes_from_tags() {
+        let record = chat(&[("id", "m1"), ("color", ""), ("emotes", "")], "hi");
+        match FeedItem::chat(&record) {
+            FeedItem::Chat {
+                id, color, emotes, ..
+            } => {
+                assert_eq!(id.as_deref(), Some("m1"));
+                assert_eq!(color, None);
+                assert!(emotes.is_empty());
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(FeedItem::translation(&record), None);
+    }
+
+    async fn next_json<S>(client: &mut S) -> serde_json::Value
+    where
+        S: futures_util::Stream<
+                Item = Result<
+                    tokio_tungstenite::tungstenite::Message,
+                    tokio_tungstenite::tungstenite::Error,
+                >,
+            > + Unpin,
+    {
+        let text = client.next().await.unwrap().unwrap().into_text().unwrap();
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn websocket_client_receives_published_items() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let overlay = Arc::new(Overlay::default());
+        let server = tokio::spawn(serve(listener, overlay.clone()));
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        let mut record = chat(
+            &[("id", "m1"), ("color", "#FF0000"), ("emotes", "25:0-4")],
+            "Kappa こんにちは",
+        );
+        overlay.publish(&FeedItem::chat(&record));
+        record.translation = Some(String::from("Kappa hello"));
+        overlay.publish(&FeedItem::translation(&record).unwrap());
+
+        let first = next_json(&mut client).await;
+        assert_eq!(first["type"], "chat");
+        assert_eq!(first["user_name"], "Alice");
+        assert_eq!(first["color"], "#FF0000");
+        assert_eq!(
+            first["emotes"],
+            serde_json::json!([{"id": "25", "start": 0, "end": 4}])
+        );
+        let second = next_json(&mut client).await;
+        assert_eq!(
+            second,
+            serde_json::json!({"type": "translation", "id": "m1", "text": "Kappa hello"})
+        );
+        server.abort();
+    }
+}
@@ -0,0 +1,299 @@
+//! read-chat の実行中に設定ファイルを読み直す。
+//!
+//! 設定ファイルの更新時刻を一定間隔で見て、変わっていれば読み込み直す。読み上げの
+//! 経路やテンプレート、翻訳コマンドなど接続に関わらないキーだけを `watch` で各タスクへ
+//! まとめて差し替え、認証情報やチャンネルなど接続し直さないと効かないキーは変更を
+//! ログに出すだけにする。
+
+use crate::settings::{ConfigSource, Settings};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+
+/// 設定ファイルを見に行く間隔。
+const POLL_INTERVAL_SECS: u64 = 2;
+
+/// 実行中のタスクが参照する設定。`borrow()` した時点の値をその処理の間ずっと使う。
+pub type LiveSettings = watch::Receiver<Arc<Settings>>;
+
+/// キーごとの扱い。`true` は実行中に差し替えられる。
+fn changed_keys(old: &Settings, new: &Settings) -> Vec<(&'static str, bool)> {
+    let keys = [
+        ("operations", true, old.operations != new.operations),
+        (
+            "speech_address",
+            true,
+            old.speech_address != new.speech_address,
+        ),
+        (
+            "greeting_template",
+            true,
+            old.greeting_template != new.greeting_template,
+        ),
+        (
+            "translate_command",
+            true,
+            old.translate_command != new.translate_command,
+        ),
+        (
+            "follow_storm.summary_template",
+            true,
+            old.follow_storm.summary_template != new.follow_storm.summary_template,
+        ),
+        ("chat", true, old.chat != new.chat),
+        ("follow", true, old.follow != new.follow),
+        ("raid", true, old.raid != new.raid),
+        ("greetings", true, old.greetings != new.greetings),
+        ("voices", true, old.voices != new.voices),
+        ("timers", true, old.timers != new.timers),
+        (
+            "stream_state.online_text",
+            true,
+            old.stream_state.online_text != new.stream_state.online_text,
+        ),
+        (
+            "stream_state.pause_operations",
+            true,
+            old.stream_state.pause_operations != new.stream_state.pause_operations,
+        ),
+        (
+            "stream_state.resume_operations",
+            true,
+            old.stream_state.resume_operations != new.stream_state.resume_operations,
+        ),
+        ("client_id", false, old.client_id != new.client_id),
+        (
+            "client_secret",
+            false,
+            old.client_secret != new.client_secret,
+        ),
+        ("channel", false, old.channel != new.channel),
+        ("username", false, old.username != new.username),
+        (
+            "listen_address",
+            false,
+            old.listen_address != new.listen_address,
+        ),
+        ("db_dir", false, old.db_dir != new.db_dir),
+        ("db_name", false, old.db_name != new.db_name),
+        ("scopes", false, old.scopes != new.scopes),
+        ("chat_log_dir", false, old.chat_log_dir != new.chat_log_dir),
+        ("control", false, old.control != new.control),
+        ("overlay", false, old.overlay != new.overlay),
+        (
+            "encrypt_store",
+            false,
+            old.encrypt_store != new.encrypt_store,
+        ),
+        ("accounts", false, old.accounts != new.accounts),
+        (
+            "follow_storm.threshold",
+            false,
+            old.follow_storm.threshold != new.follow_storm.threshold,
+        ),
+        (
+            "follow_storm.window_secs",
+            false,
+            old.follow_storm.window_secs != new.follow_storm.window_secs,
+        ),
+        (
+            "follow_storm.mitigation",
+            false,
+            old.follow_storm.mitigation != new.follow_storm.mitigation,
+        ),
+        (
+            "stream_state.action",
+            false,
+            old.stream_state.action != new.stream_state.action,
+        ),
+    ];
+    keys.into_iter()
+        .filter(|(_, _, changed)| *changed)
+        .map(|(key, reloadable, _)| (key, reloadable))
+        .collect()
+}
+
+/// 実行中の設定に、新しい設定の差し替えられるキーだけを当てる。
+fn merge(running: &Settings, new: &Settings) -> Settings {
+    let mut merged = running.clone();
+    merged.operations = new.operations.clone();
+    merged.speech_address = new.speech_address.clone();
+    merged.greeting_template = new.greeting_template.clone();
+    merged.translate_command = new.translate_command.clone();
+    merged.follow_storm.summary_template = new.follow_storm.summary_template.clone();
+    merged.chat = new.chat.clone();
+    merged.follow = new.follow.clone();
+    merged.raid = new.raid.clone();
+    merged.greetings = new.greetings.clone();
+    merged.voices = new.voices.clone();
+    merged.timers = new.timers.clone();
+    merged.stream_state.online_text = new.stream_state.online_text.clone();
+    merged.stream_state.pause_operations = new.stream_state.pause_operations.clone();
+    merged.stream_state.resume_operations = new.stream_state.resume_operations.clone();
+    merged
+}
+
+/// 読み込み元のファイルの更新時刻。無いファイルは `None` として並べる。
+fn stamps(source: &ConfigSource) -> Vec<Option<SystemTime>> {
+    source
+        .files()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// 新しく読んだ設定を反映する。差し替えたキーがあれば `true`。
+fn apply(running: &Settings, new: &Settings, tx: &watch::Sender<Arc<Settings>>) -> bool {
+    let changed = changed_keys(running, new);
+    let (reloaded, restart): (Vec<_>, Vec<_>) = changed.iter().partition(|(_, r)| *r);
+    if !restart.is_empty() {
+        let keys: Vec<&str> = restart.iter().map(|(k, _)| *k).collect();
+        warn!(
+            "config: {} changed; restart read-chat to apply",
+            keys.join(", ")
+        );
+    }
+    if reloaded.is_empty() {
+        return false;
+    }
+    let keys: Vec<&str> = reloaded.iter().map(|(k, _)| *k).collect();
+    info!("config: reloaded {}", keys.join(", "));
+    tx.send_replace(Arc::new(merge(running, new)));
+    true
+}
+
+/// 設定ファイルを見張り、変わるたびに読み直して `tx` へ流す。読み込みに失敗したら
+/// 警告だけ出して前の設定のまま続ける。
+pub async fn watch_config(source: ConfigSource, tx: watch::Sender<Arc<Settings>>) {
+    let mut last = stamps(&source);
+    // 再起動が要るキーの警告を毎回出さないよう、最後に読めた設定と比べる
+    let mut last_loaded = (**tx.borrow()).clone();
+    loop {
+        tokio::time::sleep(Duration::from_secs(POLL_INTERVAL_SECS)).await;
+        let now = stamps(&source);
+        if now == last {
+            continue;
+        }
+        last = now;
+        let new = match source.load() {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("config: failed to reload, keep the current settings: {}", e);
+                continue;
+            }
+        };
+        if changed_keys(&last_loaded, &new).is_empty() {
+            continue;
+        }
+        let running = tx.borrow().clone();
+        apply(&running, &new, &tx);
+        last_loaded = new;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Settings {
+        Settings {
+            channel: String::from("ch"),
+            operations: vec![String::from("o:/tts?i=1")],
+            greeting_template: String::from("user_name さん"),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn reloadable_keys_are_applied_at_once() {
+        let running = base();
+        let mut new = base();
+        new.operations = vec![String::from("o:/tts?i=2")];
+        new.greeting_template = String::from("ようこそ user_name さん");
+        new.greetings.returning_template = String::from("user_name さん、また来てくれた");
+        let (tx, rx) = watch::channel(Arc::new(running.clone()));
+
+        assert!(apply(&running, &new, &tx));
+        let live = rx.borrow();
+        assert_eq!(live.operations, new.operations);
+        assert_eq!(live.greeting_template, new.greeting_template);
+        assert_eq!(live.greetings, new.greetings);
+        assert!(changed_keys(&live, &new).is_empty());
+    }
+
+    #[test]
+    fn restart_keys_are_not_applied() {
+        let running = base();
+        let mut new = base();
+        new.channel = String::from("other");
+        new.client_secret = String::from("rotated");
+        let (tx, rx) = watch::channel(Arc::new(running.clone()));
+
+        assert!(!apply(&running, &new, &tx));
+        assert_eq!(rx.borrow().channel, "ch");
+        assert_eq!(
+            changed_keys(&running, &new),
+            vec![("client_secret", false), ("channel", false)]
+        );
+    }
+
+    #[test]
+    fn mixed_change_keeps_running_connection_settings() {
+        let running = base();
+        let mut new = base();
+        new.channel = String::from("other");
+        new.translate_command = String::from("translate2");
+
+        let merged = merge(&running, &new);
+        assert_eq!(merged.channel, "ch");
+        assert_eq!(merged.translate_command, "translate2");
+    }
+
+    #[test]
+    fn timers_are_reloaded() {
+        let running = base();
+        let mut new = base();
+        new.timers = vec![crate::settings::TimerSettings {
+            message: String::from("Discord はこちら"),
+            interval_secs: 600,
+            min_chat_messages: 0,
+            speak: false,
+        }];
+        let (tx, rx) = watch::channel(Arc::new(running.clone()));
+
+        assert!(apply(&running, &new, &tx));
+        assert_eq!(rx.borrow().timers, new.timers);
+        assert!(changed_keys(&rx.borrow(), &new).is_empty());
+    }
+
+    #[tokio::test]
+    async fn edited_file_is_picked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let body = |ops: &str| {
+            format!(
+                "client_id = \"id\"\nclient_secret = \"s\"\nchannel = \"ch\"\nusername = \"u\"\n\
+                 speech_address = \"http://localhost:8080\"\noperations = [\"{ops}\"]\n\
+                 translate_command = \"translate\"\n"
+            )
+        };
+        std::fs::write(&path, body("o:/tts?i=1")).unwrap();
+        let source = ConfigSource {
+            config_file: path.clone(),
+            cli_config: None,
+            default_db_dir: dir.path().to_path_buf(),
+        };
+        let (tx, mut rx) = watch::channel(Arc::new(source.load().unwrap()));
+        let watcher = tokio::spawn(watch_config(source, tx));
+
+        // 更新時刻の粒度が粗いファイルシステムでも変化が見えるよう、少し待ってから書く
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&path, body("o:/tts?i=7")).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.borrow().operations, vec![String::from("o:/tts?i=7")]);
+        watcher.abort();
+    }
+}
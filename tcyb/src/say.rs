@@ -0,0 +1,313 @@
+//! `tcyb say` — bot としてチャットに投稿する。
+//!
+//! 通常は Helix の Send Chat Message で送る（401 なら HelixClient が 1 度だけ更新する）。
+//! Helix は `/me` を受け付けないので、`--me` のときだけ IRC に繋いで ACTION を送り、
+//! Twitch の `USERSTATE`（受理）か `NOTICE`（拒否）を待って結果を決める。
+
+use crate::api;
+use crate::helix::{HelixClient, HelixError};
+use crate::irc::connect_and_authorize;
+use crate::settings::Settings;
+use crate::yomiage::IRC_CONNECT_ADDR;
+use anyhow::{anyhow, bail};
+use futures_util::{SinkExt, StreamExt};
+use log::info;
+use reqwest::StatusCode;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// 接続から投稿の受理・拒否が分かるまで待つ時間。
+const IRC_CONFIRM_TIMEOUT_SECS: u64 = 10;
+
+pub struct SayOptions {
+    /// 返信先のメッセージ ID。
+    pub reply_to: Option<String>,
+    /// `/me`（ACTION）として送る。
+    pub me: bool,
+}
+
+pub async fn say(
+    helix: &mut HelixClient,
+    settings: &Settings,
+    text: &str,
+    options: &SayOptions,
+) -> anyhow::Result<()> {
+    if text.trim().is_empty() {
+        bail!("投稿する文が空です");
+    }
+    if options.me {
+        say_irc(helix, settings, text, options).await
+    } else {
+        say_helix(helix, settings, text, options).await
+    }
+}
+
+/// Helix の失敗を「投稿できない」理由として言い直す。
+fn not_allowed(err: HelixError, settings: &Settings) -> anyhow::Error {
+    match err.status() {
+        Some(StatusCode::UNAUTHORIZED) => anyhow!(
+            "トークンが無効です。`tcyb auth-code --account {}` をやり直してください: {}",
+            settings.accounts.chat,
+            err
+        ),
+        Some(StatusCode::FORBIDDEN) => anyhow!(
+            "{} は #{} に投稿できません（user:write:chat スコープが無いか、BAN・タイムアウト中）: {}",
+            settings.username,
+            settings.channel,
+            err
+        ),
+        _ => err.into(),
+    }
+}
+
+async fn say_helix(
+    helix: &mut HelixClient,
+    settings: &Settings,
+    text: &str,
+    options: &SayOptions,
+) -> anyhow::Result<()> {
+    let sender_id = helix.user_id().await?;
+    let channel = api::get_user(helix, &settings.channel).await?;
+    let Some(channel) = channel.data.into_iter().next() else {
+        bail!("channel not found");
+    };
+    let sent = api::send_chat_message(
+        helix,
+        &channel.id,
+        &sender_id,
+        text,
+        options.reply_to.as_deref(),
+    )
+    .await
+    .map_err(|e| not_allowed(e, settings))?;
+    match sent {
+        Some(m) if m.is_sent => {
+            info!("sent {}", m.message_id);
+            Ok(())
+        }
+        Some(m) => match m.drop_reason {
+            Some(r) => bail!("投稿が拒否されました（{}）: {}", r.code, r.message),
+            None => bail!("投稿が拒否されました"),
+        },
+        None => bail!("Send Chat Message の応答が空でした"),
+    }
+}
+
+/// IRC で送る 1 行。
+fn format_privmsg(channel: &str, text: &str, reply_to: Option<&str>, me: bool) -> String {
+    let tags = match reply_to {
+        Some(id) => format!("@reply-parent-msg-id={id} "),
+        None => String::new(),
+    };
+    let body = if me {
+        format!("\u{1}ACTION {text}\u{1}")
+    } else {
+        text.to_string()
+    };
+    format!("{tags}PRIVMSG #{channel} :{body}")
+}
+
+/// 投稿の成否を決める IRC の行。
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    /// JOIN が済んだ（`ROOMSTATE`）。
+    Joined,
+    /// 投稿が受理された（送信後の `USERSTATE`）。
+    State,
+    /// `NOTICE`。投稿の拒否やログイン失敗。
+    Notice(String),
+    Ping,
+    Other,
+}
+
+fn classify(line: &str) -> Reply {
+    let rest = match line.strip_prefix('@') {
+        Some(tagged) => tagged.split_once(' ').map_or("", |(_, r)| r),
+        None => line,
+    };
+    let rest = match rest.strip_prefix(':') {
+        Some(prefixed) => prefixed.split_once(' ').map_or("", |(_, r)| r),
+        None => rest,
+    };
+    let command = rest.split(' ').next().unwrap_or_default();
+    match command {
+        "ROOMSTATE" => Reply::Joined,
+        "USERSTATE" => Reply::State,
+        "NOTICE" => Reply::Notice(
+            rest.split_once(" :")
+                .map_or(rest, |(_, text)| text)
+                .to_string(),
+        ),
+        "PING" => Reply::Ping,
+        _ => Reply::Other,
+    }
+}
+
+async fn say_irc(
+    helix: &mut HelixClient,
+    settings: &Settings,
+    text: &str,
+    options: &SayOptions,
+) -> anyhow::Result<()> {
+    // 接続前に検証し、NICK に使う login を得る（失効していればここで更新される）
+    let login = helix
+        .validate()
+        .await
+        .map_err(|e| not_allowed(e, settings))?
+        .login
+        .ok_or(HelixError::UserNotFound)?;
+    let url = url::Url::parse(IRC_CONNECT_ADDR)?;
+    let line = format_privmsg(
+        &settings.channel,
+        text,
+        options.reply_to.as_deref(),
+        options.me,
+    );
+    post_irc(&url, helix.access_token(), &login, &settings.channel, &line).await
+}
+
+/// `url` の IRC に `login` として繋いで `line` を送り、受理か拒否が分かるまで待つ。
+async fn post_irc(
+    url: &url::Url,
+    access_token: &str,
+    login: &str,
+    channel: &str,
+    line: &str,
+) -> anyhow::Result<()> {
+    let mut ws = connect_and_authorize(url, access_token, login, channel).await?;
+    let confirm = async {
+        let mut sent = false;
+        while let Some(msg) = ws.next().await {
+            let msg = msg?;
+            if !msg.is_text() {
+                continue;
+            }
+            for received in msg.into_text()?.lines() {
+                match classify(received) {
+                    Reply::Joined if !sent => {
+                        ws.send(Message::Text(line.to_string())).await?;
+                        sent = true;
+                    }
+                    Reply::State if sent => return Ok(()),
+                    Reply::Notice(notice) => {
+                        bail!("{} は #{} に投稿できません: {}", login, channel, notice)
+                    }
+                    Reply::Ping => {
+                        ws.send(Message::Text(String::from("PONG :tmi.twitch.tv")))
+                            .await?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        bail!("投稿の結果が分かる前に IRC の接続が切れました")
+    };
+    let result = tokio::time::timeout(Duration::from_secs(IRC_CONFIRM_TIMEOUT_SECS), confirm)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "{}s 待っても投稿の結果が分かりませんでした",
+                IRC_CONFIRM_TIMEOUT_SECS
+            )
+        })?;
+    let _ = ws.close(None).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Twitch の IRC の代わり。`twitch.tv/commands` を要求されたときだけ ROOMSTATE と
+    /// USERSTATE（または NOTICE）を返す。受け取った行を返す。
+    async fn fake_irc(listener: TcpListener, reject: Option<&'static str>) -> Vec<String> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut received: Vec<String> = Vec::new();
+        let mut commands = false;
+        while let Some(Ok(Message::Text(line))) = ws.next().await {
+            if line.starts_with("CAP REQ") && line.contains("twitch.tv/commands") {
+                commands = true;
+            }
+            let reply = if line.starts_with("JOIN") {
+                Some(String::from(":tmi.twitch.tv ROOMSTATE #ch"))
+            } else if line.starts_with("PRIVMSG") {
+                Some(match reject {
+                    Some(notice) => format!(":tmi.twitch.tv NOTICE #ch :{notice}"),
+                    None => String::from(":tmi.twitch.tv USERSTATE #ch"),
+                })
+            } else {
+                None
+            };
+            received.push(line);
+            if let Some(reply) = reply.filter(|_| commands) {
+                if ws.send(Message::Text(reply)).await.is_err() {
+                    break;
+                }
+            }
+        }
+        received
+    }
+
+    async fn post_to_fake(
+        line: &str,
+        reject: Option<&'static str>,
+    ) -> (anyhow::Result<()>, Vec<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(fake_irc(listener, reject));
+        let result = post_irc(&url, "token", "bot", "ch", line).await;
+        (result, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn action_is_sent_after_join_and_confirmed_by_userstate() {
+        let line = format_privmsg("ch", "waves", None, true);
+        let (result, received) = post_to_fake(&line, None).await;
+        result.unwrap();
+        assert_eq!(received[0], "CAP REQ :twitch.tv/tags twitch.tv/commands");
+        assert!(received.contains(&String::from("NICK bot")));
+        assert_eq!(received.last(), Some(&line));
+    }
+
+    #[tokio::test]
+    async fn notice_after_privmsg_is_an_error() {
+        let line = format_privmsg("ch", "waves", None, true);
+        let (result, _) = post_to_fake(&line, Some("You are banned")).await;
+        assert!(result.unwrap_err().to_string().contains("You are banned"));
+    }
+
+    #[test]
+    fn privmsg_carries_reply_tag_and_action() {
+        assert_eq!(
+            format_privmsg("ch", "hello", None, false),
+            "PRIVMSG #ch :hello"
+        );
+        assert_eq!(
+            format_privmsg("ch", "waves", Some("abc"), true),
+            "@reply-parent-msg-id=abc PRIVMSG #ch :\u{1}ACTION waves\u{1}"
+        );
+    }
+
+    #[test]
+    fn classify_reads_command_after_tags_and_prefix() {
+        assert_eq!(
+            classify("@emote-only=0;room-id=1 :tmi.twitch.tv ROOMSTATE #ch"),
+            Reply::Joined
+        );
+        assert_eq!(
+            classify("@badges=;color= :tmi.twitch.tv USERSTATE #ch"),
+            Reply::State
+        );
+        assert_eq!(
+            classify("@msg-id=msg_banned :tmi.twitch.tv NOTICE #ch :You are permanently banned from talking in ch."),
+            Reply::Notice(String::from("You are permanently banned from talking in ch."))
+        );
+        assert_eq!(classify("PING :tmi.twitch.tv"), Reply::Ping);
+        assert_eq!(
+            classify(":tmi.twitch.tv 001 bot :Welcome, GLHF!"),
+            Reply::Other
+        );
+    }
+}
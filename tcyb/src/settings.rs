@@ -1,8 +1,9 @@
 use anyhow::Context;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::{fmt::Debug, path::Path, path::PathBuf};
 
-#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
 pub struct Settings {
     pub client_id: String,
     pub client_secret: String,
@@ -15,6 +16,290 @@ pub struct Settings {
     pub db_dir: PathBuf,
     pub db_name: String,
     pub translate_command: String,
+    /// auth-code / auth-device で要求するスコープ。
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// トークンストアをパスフレーズで暗号化する。
+    #[serde(default)]
+    pub encrypt_store: bool,
+    #[serde(default)]
+    pub accounts: AccountSettings,
+    #[serde(default)]
+    pub follow_storm: FollowStormSettings,
+    #[serde(default)]
+    pub stream_state: StreamStateSettings,
+    #[serde(default)]
+    pub chat: EventSpeech,
+    #[serde(default)]
+    pub follow: EventSpeech,
+    #[serde(default)]
+    pub raid: RaidSettings,
+    #[serde(default)]
+    pub greetings: GreetingSettings,
+    #[serde(default)]
+    pub voices: VoiceSettings,
+    #[serde(default)]
+    pub ban_bots: BanBotsSettings,
+    /// チャットとイベントの記録を置くディレクトリ。未記入なら `<db_dir>/logs`。
+    #[serde(default)]
+    pub chat_log_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub timers: Vec<TimerSettings>,
+    #[serde(default)]
+    pub control: ControlSettings,
+    #[serde(default)]
+    pub overlay: OverlaySettings,
+}
+
+impl Settings {
+    pub fn chat_log_dir(&self) -> PathBuf {
+        self.chat_log_dir
+            .clone()
+            .unwrap_or_else(|| self.db_dir.join("logs"))
+    }
+}
+
+/// tcyb の全機能が使うスコープ。機能を絞りたい場合だけ `scopes` で上書きする。
+pub fn default_scopes() -> Vec<String> {
+    [
+        "chat:read",
+        "chat:edit",
+        "user:write:chat",
+        "moderator:manage:banned_users",
+        "channel:moderate",
+        "moderator:read:chatters",
+        "moderator:read:followers",
+        "user:read:follows",
+        "moderator:manage:chat_settings",
+        "moderator:manage:shield_mode",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// `[accounts]` セクション。機能ごとにトークンストアのどのアカウントを使うか。
+/// 未記入なら全機能が `auth-code` の既定アカウントを使う。
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct AccountSettings {
+    /// read-chat の IRC 接続（読み上げと翻訳返信）。
+    pub chat: String,
+    /// EventSub 購読（フォロー挨拶・荒らし緩和・配信状態）と `eventsub` サブコマンド。
+    pub events: String,
+    /// `ban-bots` / `show-chatters`。
+    pub moderation: String,
+    /// `show-user` / `show-followings`。フォロー中の一覧はトークンの持ち主の分しか取れない。
+    pub lookup: String,
+}
+
+impl Default for AccountSettings {
+    fn default() -> Self {
+        let default = String::from(crate::store::DEFAULT_ACCOUNT);
+        Self {
+            chat: default.clone(),
+            events: default.clone(),
+            moderation: default.clone(),
+            lookup: default,
+        }
+    }
+}
+
+/// `[chat]` / `[follow]` セクション。`chains` の各要素が 1 本の vstc chain で、全部を
+/// まとめて送る。空なら `operations` を 1 本の chain として使う。
+#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct EventSpeech {
+    pub chains: Vec<Vec<String>>,
+}
+
+/// `[raid]` セクション。`template` が空ならレイドを読み上げない。
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct RaidSettings {
+    pub chains: Vec<Vec<String>>,
+    pub template: String,
+}
+
+impl Default for RaidSettings {
+    fn default() -> Self {
+        Self {
+            chains: Vec::new(),
+            template: String::from("from_name さんが viewers 人でレイドしてくれました。"),
+        }
+    }
+}
+
+/// `[greetings]` セクション。初見さん（`first-msg`）と常連さん（`returning-chatter`）の
+/// その配信で最初の発言の前に読み上げる。テンプレートが空ならその挨拶はしない。
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct GreetingSettings {
+    pub chains: Vec<Vec<String>>,
+    pub first_template: String,
+    pub returning_template: String,
+}
+
+impl GreetingSettings {
+    pub fn enabled(&self) -> bool {
+        !self.first_template.is_empty() || !self.returning_template.is_empty()
+    }
+}
+
+impl Default for GreetingSettings {
+    fn default() -> Self {
+        Self {
+            chains: Vec::new(),
+            first_template: String::from("初見さんいらっしゃい。user_name さん"),
+            returning_template: String::from("user_name さん、おかえりなさい。"),
+        }
+    }
+}
+
+/// `tts` route に当てる声。未記入の `spd` / `pit` は route に書いた値のまま。
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+pub struct Voice {
+    pub speaker: u32,
+    pub spd: Option<f64>,
+    pub pit: Option<f64>,
+}
+
+/// `[voices.roles]`。バッジから決まる役割ごとの声。
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct RoleVoices {
+    pub broadcaster: Option<Voice>,
+    pub moderator: Option<Voice>,
+    pub vip: Option<Voice>,
+    pub subscriber: Option<Voice>,
+}
+
+/// `[voices]` セクション。チャットの発言者ごとに `tts` route の声を差し替える。
+/// `users`（ログイン名）→ `roles` → `pool` の順に決まり、どれにも当たらなければ
+/// route に書いた声のまま読む。
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct VoiceSettings {
+    pub users: BTreeMap<String, Voice>,
+    pub roles: RoleVoices,
+    /// 誰にも割り当てが無い発言者に、ログイン名のハッシュで選ぶ話者 ID。
+    pub pool: Vec<u32>,
+}
+
+/// `[ban_bots]` セクション。
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct BanBotsSettings {
+    /// 一覧に載っていても BAN しないログイン名。リモートの許可リストに足して使う。
+    pub allowlist: Vec<String>,
+    pub reason: String,
+}
+
+impl Default for BanBotsSettings {
+    fn default() -> Self {
+        Self {
+            allowlist: Vec::new(),
+            reason: String::from("bot"),
+        }
+    }
+}
+
+/// `[[timers]]` の 1 件。read-chat の実行中に `interval_secs` ごとに `message` を投稿する。
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct TimerSettings {
+    pub message: String,
+    pub interval_secs: u64,
+    /// 前回の投稿からこの数の（bot 以外の）チャットが無ければ、その回は投稿しない。
+    #[serde(default)]
+    pub min_chat_messages: u64,
+    /// 投稿した文をチャットの読み上げ経路でも読み上げる。
+    #[serde(default)]
+    pub speak: bool,
+}
+
+/// `[control]` セクション。`listen_address` を書いたときだけ read-chat の制御 API を開く。
+#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct ControlSettings {
+    /// localhost のアドレスのみ（`127.0.0.1:8091` など）。
+    pub listen_address: Option<String>,
+    /// 設定すると `Authorization: Bearer <token>` を要求する。
+    pub token: Option<String>,
+}
+
+/// `[overlay]` セクション。`listen_address` を書いたときだけ OBS のブラウザソース向けの
+/// チャット表示を配る。
+#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct OverlaySettings {
+    /// localhost のアドレスのみ（`127.0.0.1:8092` など）。
+    pub listen_address: Option<String>,
+}
+
+/// 配信のオンライン/オフライン時の動作。
+#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamStateAction {
+    /// 何もしない（`stream.online` / `stream.offline` を購読しない）。
+    #[default]
+    None,
+    /// オフラインで `pause_operations`、オンラインで `resume_operations` を vstc へ送る。
+    Pause,
+    /// オフラインの間は読み上げだけ止める（ログは出し続ける）。
+    Mute,
+    /// オンラインになったら `online_text` を読み上げる。
+    Announce,
+}
+
+/// `[stream_state]` セクション。
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct StreamStateSettings {
+    pub action: StreamStateAction,
+    pub online_text: String,
+    pub pause_operations: Vec<String>,
+    pub resume_operations: Vec<String>,
+}
+
+impl Default for StreamStateSettings {
+    fn default() -> Self {
+        Self {
+            action: StreamStateAction::None,
+            online_text: String::from("配信開始"),
+            pause_operations: vec![String::from("o:/pause")],
+            resume_operations: vec![String::from("o:/resume")],
+        }
+    }
+}
+
+/// 荒らし検出時に Helix で有効化する緩和策。
+#[derive(Debug, Default, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Mitigation {
+    #[default]
+    None,
+    FollowersOnly,
+    ShieldMode,
+}
+
+/// `[follow_storm]` セクション。`threshold = 0` で検出自体を無効化する。
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct FollowStormSettings {
+    pub threshold: usize,
+    pub window_secs: u64,
+    pub mitigation: Mitigation,
+    pub summary_template: String,
+}
+
+impl Default for FollowStormSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 10,
+            window_secs: 30,
+            mitigation: Mitigation::None,
+            summary_template: String::from("follow_count人がフォローしました。"),
+        }
+    }
 }
 
 const CONFIG_TEMPLATE: &str = r#"# tcyb 設定ファイル
@@ -28,6 +313,75 @@ greeting_template = "user_name さん。フォローありがとうございま
 translate_command = "translate"
 # listen_address = "localhost:8000"   # 既定値あり。変更時のみ記入
 # db_dir / db_name は OS 標準データディレクトリを既定使用（変更時のみ記入）
+# scopes = ["chat:read", "chat:edit", ...]  # 認可で要求するスコープ。既定は全機能分
+# chat_log_dir = "..."   # チャットとイベントの記録（日ごとの JSONL）の置き場所。既定は db_dir/logs
+# encrypt_store = false   # true でトークンストアを暗号化（パスフレーズは TCYB_STORE_PASSPHRASE か端末入力）
+
+# イベントごとの読み上げ経路（未記入なら operations を使う）。chains の各要素が 1 本の chain で、
+# 全部を 1 リクエストでまとめて送る
+# [chat]
+# chains = [["o:/transl?t=ja", "o:/tts?i=1", "o:/play?v=18"], ["o:/transl?t=ja", "o:/subtitle"]]
+# [follow]
+# chains = [["o:/tts?i=3", "o:/play?v=18"]]
+# [raid]
+# chains = [["o:/tts?i=3", "o:/play?v=18"]]
+# template = "from_name さんが viewers 人でレイドしてくれました。"   # 空にすると読み上げない
+# [greetings]                     # 初見さんと常連さんの配信ごとの最初の発言の前に読み上げる
+# chains = [["o:/tts?i=3", "o:/play?v=18"]]
+# first_template = "初見さんいらっしゃい。user_name さん"     # 空にすると挨拶しない
+# returning_template = "user_name さん、おかえりなさい。"
+
+# 発言者ごとの声（tts route の i / spd / pit を差し替える）。users → roles → pool の順に決まる
+# [voices]
+# pool = [1, 3, 8, 13]            # 割り当ての無い人はログイン名のハッシュでここから選ぶ
+# [voices.roles]
+# broadcaster = { speaker = 2 }
+# moderator = { speaker = 3, spd = 1.2 }
+# [voices.users]
+# some_login = { speaker = 5, pit = 0.05 }
+
+# ban-bots の設定（既定値あり。変更時のみ記入）
+# [ban_bots]
+# allowlist = ["nightbot", "streamelements"]   # 一覧に載っていても BAN しない
+# reason = "bot"
+
+# 定期投稿（read-chat の実行中に interval_secs ごとに投稿する）。何件でも書ける
+# [[timers]]
+# message = "Discord はこちら: https://discord.gg/..."
+# interval_secs = 1200
+# min_chat_messages = 5          # 前回の投稿からこの数のチャットが無ければその回は見送る
+# speak = false                  # true で投稿した文を読み上げもする
+
+# read-chat の制御用 HTTP API（listen_address を書いたときだけ開く。localhost のみ）
+# [control]
+# listen_address = "127.0.0.1:8091"
+# token = "..."                  # 設定すると Authorization: Bearer <token> を要求する
+
+# OBS のブラウザソース向けのチャット表示（listen_address を書いたときだけ開く。localhost のみ）
+# ブラウザソースの URL には http://127.0.0.1:8092/ を指定する
+# [overlay]
+# listen_address = "127.0.0.1:8092"
+
+# 機能ごとに使うアカウント（既定はすべて "default"。`tcyb auth-code --account <名前>` で追加）
+# [accounts]
+# chat = "default"                # IRC（読み上げ・翻訳返信）
+# events = "default"              # EventSub（フォロー挨拶・荒らし緩和・配信状態）。配信者のアカウント向け
+# moderation = "default"          # ban-bots / show-chatters
+# lookup = "default"              # show-user / show-followings（フォロー中の一覧はこのアカウント自身のもの）
+
+# フォロー荒らし検出（既定値あり。変更時のみ記入）
+# [follow_storm]
+# threshold = 10                  # window_secs 秒以内のフォロー数がこれに達したら荒らし扱い（0 で無効）
+# window_secs = 30
+# mitigation = "none"             # "none" | "followers_only" | "shield_mode"
+# summary_template = "follow_count人がフォローしました。"
+
+# 配信のオンライン/オフライン連動（既定値あり。変更時のみ記入）
+# [stream_state]
+# action = "none"                 # "none" | "pause" | "mute" | "announce"
+# online_text = "配信開始"          # action = "announce" で読み上げる文
+# pause_operations = ["o:/pause"]   # action = "pause" でオフライン時に送る
+# resume_operations = ["o:/resume"] # action = "pause" でオンライン時に送る
 "#;
 
 pub fn scaffold_config(config_file: &Path) -> anyhow::Result<()> {
@@ -44,6 +398,29 @@ pub fn scaffold_config(config_file: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// 設定の読み込み元。read-chat の実行中に読み直すために持ち回る。
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub config_file: PathBuf,
+    pub cli_config: Option<PathBuf>,
+    pub default_db_dir: PathBuf,
+}
+
+impl ConfigSource {
+    pub fn load(&self) -> anyhow::Result<Settings> {
+        load(
+            &self.config_file,
+            self.cli_config.as_deref(),
+            &self.default_db_dir,
+        )
+    }
+
+    /// 読み込むファイル（無くてもよい既定の設定ファイルと `--config`）。
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.config_file.as_path()).chain(self.cli_config.as_deref())
+    }
+}
+
 pub fn load(
     config_file: &Path,
     cli_config: Option<&Path>,
@@ -59,7 +436,8 @@ pub fn load(
         config::Environment::with_prefix("cb")
             .try_parsing(true)
             .list_separator(",")
-            .with_list_parse_key("operations"),
+            .with_list_parse_key("operations")
+            .with_list_parse_key("scopes"),
     );
     if let Some(path) = cli_config {
         let name = path.to_str().context("--config path is not valid UTF-8")?;
@@ -135,6 +513,27 @@ operations = ["o:/transl?t=ja"]
 translate_command = "translate"
 "#;
 
+    #[test]
+    fn load_defaults_scopes_and_reads_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let default_db = std::path::Path::new("/var/tcyb-data");
+
+        let cfg = write_config(dir.path(), FULL_CONFIG);
+        assert_eq!(
+            load(&cfg, None, default_db).unwrap().scopes,
+            default_scopes()
+        );
+
+        let cfg = write_config(
+            dir.path(),
+            &format!("{FULL_CONFIG}scopes = [\"chat:read\"]\n"),
+        );
+        assert_eq!(
+            load(&cfg, None, default_db).unwrap().scopes,
+            vec![String::from("chat:read")]
+        );
+    }
+
     #[test]
     fn load_applies_default_db_dir_when_absent() {
         let dir = tempfile::tempdir().unwrap();
@@ -158,6 +557,130 @@ translate_command = "translate"
         assert_eq!(s.db_dir, std::path::Path::new("custom-db"));
     }
 
+    #[test]
+    fn load_defaults_follow_storm_when_section_absent() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = write_config(dir.path(), FULL_CONFIG);
+
+        let s = load(&cfg, None, std::path::Path::new("/var/tcyb-data")).unwrap();
+
+        assert_eq!(s.follow_storm, FollowStormSettings::default());
+    }
+
+    #[test]
+    fn load_reads_follow_storm_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = format!(
+            "{}\n[follow_storm]\nthreshold = 3\nmitigation = \"shield_mode\"\n",
+            FULL_CONFIG
+        );
+        let cfg = write_config(dir.path(), &body);
+
+        let s = load(&cfg, None, std::path::Path::new("/var/tcyb-data")).unwrap();
+
+        assert_eq!(s.follow_storm.threshold, 3);
+        assert_eq!(s.follow_storm.mitigation, Mitigation::ShieldMode);
+        // 未記入のキーは既定値のまま
+        assert_eq!(s.follow_storm.window_secs, 30);
+    }
+
+    #[test]
+    fn load_reads_stream_state_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = format!("{}\n[stream_state]\naction = \"mute\"\n", FULL_CONFIG);
+        let cfg = write_config(dir.path(), &body);
+
+        let s = load(&cfg, None, std::path::Path::new("/var/tcyb-data")).unwrap();
+
+        assert_eq!(s.stream_state.action, StreamStateAction::Mute);
+        assert_eq!(s.stream_state.online_text, "配信開始");
+        assert_eq!(
+            s.stream_state.pause_operations,
+            vec!["o:/pause".to_string()]
+        );
+    }
+
+    #[test]
+    fn load_reads_accounts_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = format!(
+            "{}\n[accounts]\nevents = \"broadcaster\"\nlookup = \"broadcaster\"\n",
+            FULL_CONFIG
+        );
+        let cfg = write_config(dir.path(), &body);
+
+        let s = load(&cfg, None, std::path::Path::new("/var/tcyb-data")).unwrap();
+
+        assert_eq!(s.accounts.events, "broadcaster");
+        assert_eq!(s.accounts.lookup, "broadcaster");
+        // 未記入の機能は既定アカウントのまま
+        assert_eq!(s.accounts.chat, crate::store::DEFAULT_ACCOUNT);
+        assert_eq!(s.accounts.moderation, crate::store::DEFAULT_ACCOUNT);
+    }
+
+    #[test]
+    fn load_reads_event_sections() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = format!(
+            "{}\n[chat]\nchains = [[\"o:/tts?i=1\"], [\"o:/subtitle\"]]\n[raid]\ntemplate = \"\"\n",
+            FULL_CONFIG
+        );
+        let cfg = write_config(dir.path(), &body);
+
+        let s = load(&cfg, None, std::path::Path::new("/var/tcyb-data")).unwrap();
+
+        assert_eq!(
+            s.chat.chains,
+            vec![
+                vec![String::from("o:/tts?i=1")],
+                vec![String::from("o:/subtitle")]
+            ]
+        );
+        assert!(s.follow.chains.is_empty());
+        assert!(s.raid.template.is_empty());
+    }
+
+    #[test]
+    fn load_reads_voices_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = format!(
+            "{}\n[voices]\npool = [1, 3]\n[voices.roles]\nmoderator = {{ speaker = 3, spd = 1.2 }}\n[voices.users]\nalice = {{ speaker = 5 }}\n",
+            FULL_CONFIG
+        );
+        let cfg = write_config(dir.path(), &body);
+
+        let s = load(&cfg, None, std::path::Path::new("/var/tcyb-data")).unwrap();
+
+        assert_eq!(s.voices.pool, vec![1, 3]);
+        assert_eq!(
+            s.voices.roles.moderator,
+            Some(Voice {
+                speaker: 3,
+                spd: Some(1.2),
+                pit: None
+            })
+        );
+        assert_eq!(s.voices.users["alice"].speaker, 5);
+    }
+
+    #[test]
+    fn load_reads_timers() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = format!(
+            "{}\n[[timers]]\nmessage = \"discord\"\ninterval_secs = 1200\nmin_chat_messages = 5\n[[timers]]\nmessage = \"hydrate\"\ninterval_secs = 600\nspeak = true\n",
+            FULL_CONFIG
+        );
+        let cfg = write_config(dir.path(), &body);
+
+        let s = load(&cfg, None, std::path::Path::new("/var/tcyb-data")).unwrap();
+
+        assert_eq!(s.timers.len(), 2);
+        assert_eq!(s.timers[0].min_chat_messages, 5);
+        assert!(!s.timers[0].speak);
+        assert_eq!(s.timers[1].min_chat_messages, 0);
+        assert!(s.timers[1].speak);
+    }
+
     #[test]
     fn load_errors_when_required_secret_missing() {
         let dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,112 @@
+//! イベントごとの読み上げ経路。
+//!
+//! チャット・フォロー・レイド・挨拶はそれぞれ `[chat]` / `[follow]` / `[raid]` /
+//! `[greetings]` セクションの `chains` を持つ。複数の chain は 1 つの `Command` にまとめて送るので、vstreamer には
+//! 1 リクエストとして届き、どの chain も同じ入力と trace id を見る（ADR-0018）。
+
+use crate::settings::{Settings, Voice};
+use crate::voice::apply_voice;
+
+/// 読み上げのきっかけになるイベント。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeechEvent {
+    Chat,
+    Follow,
+    Raid,
+    Greeting,
+}
+
+impl Settings {
+    /// `event` で送る chain の並び。セクションに `chains` が無ければ `operations` を
+    /// 1 本の chain として使う。
+    pub fn chains(&self, event: SpeechEvent) -> Vec<Vec<String>> {
+        let chains = match event {
+            SpeechEvent::Chat => &self.chat.chains,
+            SpeechEvent::Follow => &self.follow.chains,
+            SpeechEvent::Raid => &self.raid.chains,
+            SpeechEvent::Greeting => &self.greetings.chains,
+        };
+        if chains.is_empty() {
+            vec![self.operations.clone()]
+        } else {
+            chains.clone()
+        }
+    }
+}
+
+/// `chains` をまとめて vstreamer へ送る。
+pub async fn speak(uri: &str, chains: &[Vec<String>], text: String) -> Result<(), vstc::VstcError> {
+    speak_as(uri, chains, text, None).await
+}
+
+/// `voice` があれば `tts` route の声を差し替えてから送る。
+pub async fn speak_as(
+    uri: &str,
+    chains: &[Vec<String>],
+    text: String,
+    voice: Option<&Voice>,
+) -> Result<(), vstc::VstcError> {
+    let mut routes = chains
+        .iter()
+        .map(|chain| {
+            chain
+                .iter()
+                .map(|op| vstc::parse_route(op))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(voice) = voice {
+        apply_voice(&mut routes, voice);
+    }
+    vstc::process_chains_with_operand(
+        uri,
+        routes,
+        vstc::RouteOperand {
+            text,
+            ..vstc::RouteOperand::default()
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+/// レイド通知の読み上げ文。`from_name` と `viewers` を置き換える。
+pub fn raid_text(template: &str, from_name: &str, viewers: u64) -> String {
+    template
+        .replace("from_name", from_name)
+        .replace("viewers", &viewers.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::EventSpeech;
+
+    #[test]
+    fn section_without_chains_falls_back_to_operations() {
+        let s = Settings {
+            operations: vec![String::from("o:/tts?i=1")],
+            follow: EventSpeech {
+                chains: vec![
+                    vec![String::from("o:/tts?i=3")],
+                    vec![String::from("o:/subtitle")],
+                ],
+            },
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            s.chains(SpeechEvent::Chat),
+            vec![vec![String::from("o:/tts?i=1")]]
+        );
+        assert_eq!(s.chains(SpeechEvent::Follow).len(), 2);
+    }
+
+    #[test]
+    fn raid_text_fills_placeholders() {
+        assert_eq!(
+            raid_text("from_name さんから viewers 人のレイド", "alice", 12),
+            "alice さんから 12 人のレイド"
+        );
+    }
+}
@@ -1,12 +1,18 @@
-use crate::api::{get_tokens_by_refresh, get_user};
+use crate::api::get_tokens_by_refresh;
+use crate::cipher::{Cipher, Sealed};
+use fs2::FileExt;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum StoreError {
-    #[error("user not found")]
-    UserNotFound,
     #[error(transparent)]
     RequestError(#[from] reqwest::Error),
     #[error(transparent)]
@@ -18,63 +24,455 @@ pub struct DBStore {
     pub access_token: String,
     pub refresh_token: String,
     pub user_id: String,
+    /// 最後に `/oauth2/validate` した時点の残り有効秒数。0 は未確認か期限なし。
+    #[serde(default)]
+    pub expires_in: u64,
+    /// `expires_in` を得た時刻（Unix 秒）。0 は未確認。
+    #[serde(default)]
+    pub validated_at: u64,
+    #[serde(default)]
+    pub scopes: Vec<String>,
 }
 
-/// Persist freshly obtained tokens, creating the store on first use
-/// (first-time `auth-code`, or after the store location moved). An existing
-/// record's `user_id` is preserved so a re-auth doesn't drop it.
+/// 既定のアカウント名。アカウント対応前の単一トークンの記録もこの名前で読む。
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// 現在のストアの版。形を変えるときは上げて `MIGRATIONS` に移行を足す。
+///
+/// - 1: `DBStore` 1 つだけ（`version` キー無し）
+/// - 2: `{"version": 2, "accounts": {"<名前>": DBStore}}`
+pub const STORE_VERSION: u64 = 2;
+
+/// `MIGRATIONS[i]` は版 `i + 1` の記録を版 `i + 2` へ上げる。
+const MIGRATIONS: [fn(Value) -> Value; 1] = [v1_to_v2];
+
+/// 旧版が作業ディレクトリに作っていたトークン DB の置き場所。
+const LEGACY_DB_DIR: &str = "db";
+
+/// ストアに保存するアカウントごとのトークン。
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Accounts {
+    pub accounts: BTreeMap<String, DBStore>,
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+/// `version` キーの無い記録は形から版を決める（版 2 の途中まで `version` を書いていなかった）。
+fn version_of(doc: &Value) -> u64 {
+    match doc.get("version").and_then(Value::as_u64) {
+        Some(v) => v,
+        None if doc.get("accounts").is_some() => 2,
+        None => 1,
+    }
+}
+
+fn v1_to_v2(doc: Value) -> Value {
+    json!({ "accounts": { DEFAULT_ACCOUNT: doc } })
+}
+
+/// 記録を現在の版まで上げる。戻り値の版は移行前のもの。
+fn migrate(mut doc: Value) -> Result<(Value, u64), Error> {
+    let from = version_of(&doc);
+    if from == 0 || from > STORE_VERSION {
+        return Err(invalid_data(format!(
+            "トークンストアの版 {} はこの tcyb では読めません（対応は {} まで）。tcyb を更新してください。",
+            from, STORE_VERSION
+        )));
+    }
+    for step in &MIGRATIONS[(from - 1) as usize..] {
+        doc = step(doc);
+    }
+    if let Some(obj) = doc.as_object_mut() {
+        obj.insert(String::from("version"), json!(STORE_VERSION));
+    }
+    Ok((doc, from))
+}
+
+/// 読んだ記録。`migrated_from` は移行が要った場合の元の版。
+struct Loaded {
+    doc: Accounts,
+    was_sealed: bool,
+    migrated_from: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+/// 1 つのストアファイル。jfs の id（`db_name`）とその実体のパスを一緒に持つ。
+struct Db {
+    jfs: jfs::Store,
+    dir: PathBuf,
+    name: String,
+}
+
+impl Db {
+    /// `db_dir` のストアを開く。まだ無く、旧版の `./db` に同名の記録があれば取り込む。
+    fn open(db_dir: &Path, db_name: &str) -> Result<Self, Error> {
+        import_legacy(Path::new(LEGACY_DB_DIR), db_dir, db_name)?;
+        Ok(Self {
+            jfs: jfs::Store::new(db_dir)?,
+            dir: db_dir.to_path_buf(),
+            name: db_name.to_string(),
+        })
+    }
+
+    fn path(&self) -> PathBuf {
+        record_path(&self.dir, &self.name)
+    }
+
+    /// 読み書きの間持つ排他ロック。手放すのはファイルを閉じたとき（guard の drop）。
+    /// jfs は書き込みで記録ファイルを作り直すことがあるので、ロックは `<名前>.lock` に掛ける。
+    fn lock(&self) -> Result<File, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path().with_extension("lock"))?;
+        file.lock_exclusive()?;
+        Ok(file)
+    }
+
+    /// 記録ファイルの更新時刻と大きさ。別プロセスが書いたかどうかの目安にする。
+    fn stamp(&self) -> Option<FileStamp> {
+        let meta = std::fs::metadata(self.path()).ok()?;
+        Some(FileStamp {
+            modified: meta.modified().ok()?,
+            len: meta.len(),
+        })
+    }
+
+    /// ロックを取って読む。
+    fn load(&self, cipher: Option<&Cipher>) -> Result<Loaded, Error> {
+        let _lock = self.lock()?;
+        self.read(cipher)
+    }
+
+    /// ロックを取ったまま読み、`f` で書き換えて書き戻す。ファイルが無ければ空から始める。
+    fn update(
+        &self,
+        cipher: Option<&Cipher>,
+        f: impl FnOnce(&mut Accounts),
+    ) -> Result<Accounts, Error> {
+        let _lock = self.lock()?;
+        let mut doc = self.read_or_empty(cipher)?;
+        f(&mut doc);
+        self.write(&doc, cipher)?;
+        Ok(doc)
+    }
+
+    /// 記録を読み、必要なら現在の版へ上げる。移行した場合は元のファイルを
+    /// `<名前>.v<版>.bak` に残してから、同じ形式（暗号化の有無）で書き直す。
+    fn read(&self, cipher: Option<&Cipher>) -> Result<Loaded, Error> {
+        let raw: Value = self.jfs.get(&self.name)?;
+        let (doc, was_sealed) = match raw.get("sealed") {
+            Some(sealed) => {
+                let sealed: Sealed =
+                    serde_json::from_value(sealed.clone()).map_err(invalid_data)?;
+                let Some(cipher) = cipher else {
+                    return Err(invalid_data(format!(
+                        "トークンストアは暗号化されています。設定に `encrypt_store = true` を追加し、\
+                         パスフレーズを {} で渡すか端末で入力してください。",
+                        crate::cipher::PASSPHRASE_ENV
+                    )));
+                };
+                let plain = cipher.open(&sealed)?;
+                (serde_json::from_slice(&plain).map_err(invalid_data)?, true)
+            }
+            None => (raw, false),
+        };
+        let (doc, from) = migrate(doc)?;
+        let loaded = Loaded {
+            doc: serde_json::from_value(doc).map_err(invalid_data)?,
+            was_sealed,
+            migrated_from: (from < STORE_VERSION).then_some(from),
+        };
+        if let Some(from) = loaded.migrated_from {
+            let backup = self.path().with_extension(format!("v{from}.bak"));
+            if !backup.exists() {
+                // 暗号化して使うストアなら、平文だった元のファイルもバックアップでは暗号化する
+                std::fs::write(
+                    &backup,
+                    resealed(std::fs::read(self.path())?, None, cipher)?,
+                )?;
+            }
+            info!(
+                "migrate token store {} from version {} to {} (backup: {})",
+                self.name,
+                from,
+                STORE_VERSION,
+                backup.display()
+            );
+            self.write(&loaded.doc, if was_sealed { cipher } else { None })?;
+        }
+        Ok(loaded)
+    }
+
+    /// ファイルが無ければ空の記録として読む。
+    fn read_or_empty(&self, cipher: Option<&Cipher>) -> Result<Accounts, Error> {
+        match self.read(cipher) {
+            Ok(loaded) => Ok(loaded.doc),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Accounts::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 移行前の記録のバックアップ（`<名前>.v<版>.bak`）。
+    fn backups(&self) -> Result<Vec<PathBuf>, Error> {
+        let prefix = format!(
+            "{}.v",
+            Path::new(&self.name)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+        );
+        let mut backups = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(".bak") {
+                backups.push(path);
+            }
+        }
+        Ok(backups)
+    }
+
+    /// 記録の形式を変えたあと、バックアップも同じ形式にそろえる。暗号化したときは
+    /// 旧版の `./db` に平文の記録が残っていれば警告する。
+    fn reseal_leftovers(&self, from: Option<&Cipher>, to: Option<&Cipher>) -> Result<(), Error> {
+        for backup in self.backups()? {
+            std::fs::write(&backup, resealed(std::fs::read(&backup)?, from, to)?)?;
+        }
+        let legacy = record_path(Path::new(LEGACY_DB_DIR), &self.name);
+        if to.is_some() && legacy.is_file() {
+            warn!(
+                "{} still holds the plaintext tokens imported earlier; delete it",
+                legacy.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// 現在の版で書く。`cipher` があれば平文はディスクに一切書かない。
+    fn write(&self, doc: &Accounts, cipher: Option<&Cipher>) -> Result<(), Error> {
+        let mut value = serde_json::to_value(doc).map_err(invalid_data)?;
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert(String::from("version"), json!(STORE_VERSION));
+        }
+        match cipher {
+            Some(cipher) => {
+                let plain = serde_json::to_vec(&value).map_err(invalid_data)?;
+                let sealed = cipher.seal(&plain)?;
+                self.jfs
+                    .save_with_id(&json!({ "sealed": sealed }), &self.name)?;
+            }
+            None => {
+                self.jfs.save_with_id(&value, &self.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ファイルの中身を `to` の形式にする。平文は暗号化し、`to` が `None` なら `from` で復号する。
+/// 既にその形式なら変えない。
+fn resealed(bytes: Vec<u8>, from: Option<&Cipher>, to: Option<&Cipher>) -> Result<Vec<u8>, Error> {
+    let sealed = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .and_then(|v| v.get("sealed").cloned());
+    match (sealed, to) {
+        (None, Some(to)) => {
+            serde_json::to_vec(&json!({ "sealed": to.seal(&bytes)? })).map_err(invalid_data)
+        }
+        (Some(sealed), None) => {
+            let sealed: Sealed = serde_json::from_value(sealed).map_err(invalid_data)?;
+            let Some(from) = from else {
+                return Err(invalid_data("暗号化されたバックアップを復号できません"));
+            };
+            Ok(from.open(&sealed)?)
+        }
+        _ => Ok(bytes),
+    }
+}
+
+fn record_path(db_dir: &Path, db_name: &str) -> PathBuf {
+    db_dir.join(db_name).with_extension("json")
+}
+
+/// `db_dir` に記録が無く `legacy_dir` にあれば、ファイルをそのまま複製する。
+/// 版の移行は次に読んだときに行う。旧ファイルは消さずに残す。
+fn import_legacy(legacy_dir: &Path, db_dir: &Path, db_name: &str) -> Result<bool, Error> {
+    let legacy = record_path(legacy_dir, db_name);
+    let current = record_path(db_dir, db_name);
+    if current.exists() || !legacy.is_file() {
+        return Ok(false);
+    }
+    std::fs::create_dir_all(db_dir)?;
+    std::fs::copy(&legacy, &current)?;
+    info!(
+        "imported legacy token store {} into {}; the old file can be deleted",
+        legacy.display(),
+        current.display()
+    );
+    Ok(true)
+}
+
+/// Persist freshly obtained tokens for `account`, creating the store on first
+/// use (first-time `auth-code`, or after the store location moved). An existing
+/// record's `user_id` is preserved so a re-auth doesn't drop it; the validation
+/// info is cleared because it described the old token. Other accounts are
+/// left untouched.
 pub fn save_tokens(
     db_dir: &Path,
     db_name: &str,
+    account: &str,
+    cipher: Option<&Cipher>,
     access_token: String,
     refresh_token: String,
 ) -> Result<(), std::io::Error> {
-    let db = jfs::Store::new(db_dir)?;
-    let obj = match db.get::<DBStore>(db_name) {
-        Ok(o) => o,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => DBStore::default(),
-        Err(e) => return Err(e),
-    };
-    let updated = DBStore {
-        access_token,
-        refresh_token,
-        ..obj
-    };
-    db.save_with_id(&updated, db_name)?;
+    let db = Db::open(db_dir, db_name)?;
+    db.update(cipher, |doc| {
+        let user_id = doc
+            .accounts
+            .remove(account)
+            .map(|o| o.user_id)
+            .unwrap_or_default();
+        doc.accounts.insert(
+            account.to_string(),
+            DBStore {
+                access_token,
+                refresh_token,
+                user_id,
+                ..DBStore::default()
+            },
+        );
+    })?;
     Ok(())
 }
 
+/// 既存の記録を読み、`to` の形式（`None` なら平文）で書き直す。
+/// `tcyb store encrypt` / `tcyb store decrypt` の本体。全アカウントをまとめて扱う。
+pub fn reseal(
+    db_dir: &Path,
+    db_name: &str,
+    from: Option<&Cipher>,
+    to: Option<&Cipher>,
+) -> Result<(), std::io::Error> {
+    let db = Db::open(db_dir, db_name)?;
+    let _lock = db.lock()?;
+    let loaded = db.read(from)?;
+    db.write(&loaded.doc, to)?;
+    db.reseal_leftovers(from, to)
+}
+
+/// ストア内の 1 アカウント分のトークンを扱う。書き込みはロックを取ってファイル全体を
+/// 読み直し、その時点の記録に変更を当てるので、他のアカウントや別プロセス
+/// （read-chat 実行中の `tcyb refresh-token` など）の書き込みを壊さない。
 pub struct Store {
-    db: jfs::Store,
-    db_name: String,
+    db: Db,
+    account: String,
     obj: DBStore,
+    cipher: Option<Cipher>,
+    /// 最後に読み書きした時点のファイルの状態。
+    seen: Option<FileStamp>,
 }
 impl Store {
-    pub fn new(db_dir: &Path, db_name: &str) -> Result<Self, std::io::Error> {
-        let db = jfs::Store::new(db_dir)?;
-        let obj = db.get::<DBStore>(db_name).map_err(|e| {
+    /// `account` のトークンを開く。`cipher` を渡すと暗号化ストアとして開き、
+    /// 平文の記録はその場で暗号化し直す。
+    pub fn new(
+        db_dir: &Path,
+        db_name: &str,
+        account: &str,
+        cipher: Option<Cipher>,
+    ) -> Result<Self, std::io::Error> {
+        let db = Db::open(db_dir, db_name)?;
+        let lock = db.lock()?;
+        let mut loaded = db.read(cipher.as_ref()).map_err(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
-                let expected = db_dir.join(db_name).with_extension("json");
                 std::io::Error::new(
                     std::io::ErrorKind::NotFound,
                     format!(
-                        "トークンストアが見つかりません ({})。`tcyb auth-code` で認証するか、\
-                         既存の db/{} をこの場所へコピーしてください。",
-                        expected.display(),
-                        db_name
+                        "トークンストアが見つかりません ({})。`tcyb auth-code` で認証してください。",
+                        db.path().display()
                     ),
                 )
             } else {
                 e
             }
         })?;
+        if cipher.is_some() && !loaded.was_sealed {
+            info!("encrypt plaintext token store {}", db_name);
+            db.write(&loaded.doc, cipher.as_ref())?;
+            db.reseal_leftovers(None, cipher.as_ref())?;
+        }
+        let Some(obj) = loaded.doc.accounts.remove(account) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "トークンストア {} にアカウント {} がありません。\
+                     `tcyb auth-code --account {}` で認証してください。",
+                    db_name, account, account
+                ),
+            ));
+        };
+        let seen = db.stamp();
+        drop(lock);
         Ok(Self {
             db,
-            db_name: String::from(db_name),
+            account: String::from(account),
             obj,
+            cipher,
+            seen,
         })
     }
 
+    /// ディスク上の自分のアカウントの記録に `f` を当てて保存し、結果を手元にも反映する。
+    /// 別プロセスがアカウントごと消していた場合は手元の記録から作り直す。
+    fn modify(&mut self, f: impl FnOnce(&mut DBStore)) -> Result<(), std::io::Error> {
+        let account = self.account.clone();
+        let fallback = self.obj.clone();
+        let mut doc = self.db.update(self.cipher.as_ref(), |doc| {
+            f(doc.accounts.entry(account).or_insert(fallback));
+        })?;
+        if let Some(obj) = doc.accounts.remove(&self.account) {
+            self.obj = obj;
+        }
+        self.seen = self.db.stamp();
+        Ok(())
+    }
+
+    /// ロックを取って読み直す。自分のアカウントが無くなっていれば手元の記録を保つ。
+    fn reload(&mut self) -> Result<(), std::io::Error> {
+        let mut loaded = self.db.load(self.cipher.as_ref())?;
+        if let Some(obj) = loaded.doc.accounts.remove(&self.account) {
+            self.obj = obj;
+        }
+        self.seen = self.db.stamp();
+        Ok(())
+    }
+
+    /// 最後に読み書きしてからファイルが変わっていれば読み直す。トークンが入れ替わっていたら `true`。
+    pub fn reload_if_changed(&mut self) -> Result<bool, std::io::Error> {
+        if self.db.stamp() == self.seen {
+            return Ok(false);
+        }
+        let before = self.obj.access_token.clone();
+        self.reload()?;
+        Ok(self.obj.access_token != before)
+    }
+
+    /// 更新の直前に読み直し、別プロセスが先にトークンを入れ替えていたらそれを使う。
+    /// 手元の古い refresh token で更新すると、相手の更新を無駄にしたり失敗したりする。
+    fn adopt_newer_tokens(&mut self) -> Result<bool, std::io::Error> {
+        let before = self.obj.access_token.clone();
+        self.reload()?;
+        Ok(self.obj.access_token != before)
+    }
+
     pub fn access_token(&self) -> &str {
         self.obj.access_token.as_str()
     }
@@ -84,35 +482,57 @@ impl Store {
         client_id: &str,
         client_secret: &str,
     ) -> Result<(), StoreError> {
+        if self.adopt_newer_tokens()? {
+            info!(
+                "tokens of account {} were refreshed by another process: use them",
+                self.account
+            );
+            return Ok(());
+        }
+        // 通信の間はロックを持たない。その間に書かれても、保存時に読み直して当てる
         let (access_token, refresh_token) =
             get_tokens_by_refresh(&self.obj.refresh_token, client_id, client_secret).await?;
-        let updated_obj = DBStore {
-            access_token,
-            refresh_token,
-            user_id: self.obj.user_id.clone(),
-        };
-        self.db.save_with_id(&updated_obj, &self.db_name)?;
-        self.obj = self.db.get::<DBStore>(&self.db_name)?;
+        self.modify(|obj| {
+            obj.access_token = access_token;
+            obj.refresh_token = refresh_token;
+            // 更新でスコープは変わらないが、期限は次の validate まで分からない
+            obj.expires_in = 0;
+            obj.validated_at = 0;
+        })?;
         Ok(())
     }
 
-    pub async fn user_id(&mut self, username: &str, client_id: &str) -> Result<String, StoreError> {
-        if self.obj.user_id.is_empty() {
-            let my_user = get_user(username, &self.obj.access_token, client_id).await?;
-            if my_user.data.is_empty() {
-                return Err(StoreError::UserNotFound);
-            }
-            let my_user_id = &my_user.data[0].id;
-            let new_user_id = my_user_id.clone();
-            let updated_obj = DBStore {
-                access_token: self.obj.access_token.clone(),
-                refresh_token: self.obj.refresh_token.clone(),
-                user_id: new_user_id,
-            };
-            self.db.save_with_id(&updated_obj, &self.db_name)?;
-            self.obj = self.db.get::<DBStore>(&self.db_name)?;
+    /// トークンの失効時刻（Unix 秒）。未確認か期限なしなら `None`。
+    pub fn expires_at(&self) -> Option<u64> {
+        if self.obj.expires_in == 0 || self.obj.validated_at == 0 {
+            return None;
         }
-        Ok(self.obj.user_id.clone())
+        Some(self.obj.validated_at + self.obj.expires_in)
+    }
+
+    pub fn set_validation(
+        &mut self,
+        expires_in: u64,
+        scopes: Vec<String>,
+        validated_at: u64,
+    ) -> Result<(), std::io::Error> {
+        // 検証したのは手元のトークン。その間に別プロセスが入れ替えていたら記録しない
+        let validated = self.obj.access_token.clone();
+        self.modify(|obj| {
+            if obj.access_token == validated {
+                obj.expires_in = expires_in;
+                obj.validated_at = validated_at;
+                obj.scopes = scopes;
+            }
+        })
+    }
+
+    pub fn user_id(&self) -> &str {
+        self.obj.user_id.as_str()
+    }
+
+    pub fn set_user_id(&mut self, user_id: String) -> Result<(), std::io::Error> {
+        self.modify(|obj| obj.user_id = user_id)
     }
 }
 
@@ -120,11 +540,21 @@ impl Store {
 mod tests {
     use super::*;
 
+    fn read_account(dir: &Path, account: &str) -> DBStore {
+        jfs::Store::new(dir)
+            .unwrap()
+            .get::<Accounts>("data.json")
+            .unwrap()
+            .accounts
+            .remove(account)
+            .unwrap()
+    }
+
     #[test]
     fn new_missing_token_store_gives_actionable_error() {
         let dir = tempfile::tempdir().unwrap();
         // No record file exists in the fresh dir, so the token store is absent.
-        let err = match Store::new(dir.path(), "data.json") {
+        let err = match Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None) {
             Ok(_) => panic!("expected an error for a missing token store"),
             Err(e) => e,
         };
@@ -146,12 +576,17 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
 
         // Fresh dir, no record yet: this must create the store, not error.
-        save_tokens(dir.path(), "data.json", "acc".into(), "ref".into()).unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "acc".into(),
+            "ref".into(),
+        )
+        .unwrap();
 
-        let saved = jfs::Store::new(dir.path())
-            .unwrap()
-            .get::<DBStore>("data.json")
-            .unwrap();
+        let saved = read_account(dir.path(), DEFAULT_ACCOUNT);
         assert_eq!(saved.access_token, "acc");
         assert_eq!(saved.refresh_token, "ref");
         assert_eq!(saved.user_id, ""); // no user id yet on a fresh bootstrap
@@ -166,19 +601,472 @@ mod tests {
                 access_token: "old".into(),
                 refresh_token: "oldr".into(),
                 user_id: "U123".into(),
+                ..DBStore::default()
             },
             "data.json",
         )
         .unwrap();
 
-        save_tokens(dir.path(), "data.json", "new".into(), "newr".into()).unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "new".into(),
+            "newr".into(),
+        )
+        .unwrap();
 
-        let saved = jfs::Store::new(dir.path())
-            .unwrap()
-            .get::<DBStore>("data.json")
-            .unwrap();
+        let saved = read_account(dir.path(), DEFAULT_ACCOUNT);
         assert_eq!(saved.access_token, "new");
         assert_eq!(saved.refresh_token, "newr");
         assert_eq!(saved.user_id, "U123"); // preserved across re-auth
     }
+
+    fn seed_v1(dir: &Path) {
+        jfs::Store::new(dir)
+            .unwrap()
+            .save_with_id(
+                &DBStore {
+                    access_token: "old".into(),
+                    refresh_token: "oldr".into(),
+                    user_id: "U123".into(),
+                    ..DBStore::default()
+                },
+                "data.json",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn v1_record_is_migrated_and_backed_up() {
+        let dir = tempfile::tempdir().unwrap();
+        seed_v1(dir.path());
+        let original = std::fs::read_to_string(dir.path().join("data.json")).unwrap();
+
+        let store = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        assert_eq!(store.access_token(), "old");
+        assert_eq!(store.user_id(), "U123");
+
+        let raw: Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("data.json")).unwrap())
+                .unwrap();
+        assert_eq!(raw["version"], json!(STORE_VERSION));
+        assert_eq!(raw["accounts"][DEFAULT_ACCOUNT]["refresh_token"], "oldr");
+        let backup = std::fs::read_to_string(dir.path().join("data.v1.bak")).unwrap();
+        assert_eq!(backup, original);
+    }
+
+    #[test]
+    fn encrypted_v1_record_stays_encrypted_after_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = Cipher::cheap("pass");
+        let v1 = serde_json::to_vec(&DBStore {
+            access_token: "acc-secret".into(),
+            ..DBStore::default()
+        })
+        .unwrap();
+        jfs::Store::new(dir.path())
+            .unwrap()
+            .save_with_id(&json!({ "sealed": cipher.seal(&v1).unwrap() }), "data.json")
+            .unwrap();
+
+        let store = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, Some(cipher)).unwrap();
+        assert_eq!(store.access_token(), "acc-secret");
+        let raw = std::fs::read_to_string(dir.path().join("data.json")).unwrap();
+        assert!(raw.contains("sealed") && !raw.contains("acc-secret"));
+    }
+
+    #[test]
+    fn newer_store_version_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        jfs::Store::new(dir.path())
+            .unwrap()
+            .save_with_id(
+                &json!({ "version": STORE_VERSION + 1, "accounts": {} }),
+                "data.json",
+            )
+            .unwrap();
+
+        let err = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("tcyb を更新"), "{err}");
+    }
+
+    #[test]
+    fn version_is_inferred_for_records_without_it() {
+        assert_eq!(version_of(&json!({ "access_token": "a" })), 1);
+        assert_eq!(version_of(&json!({ "accounts": {} })), 2);
+        assert_eq!(version_of(&json!({ "version": 7 })), 7);
+    }
+
+    #[test]
+    fn legacy_store_is_imported_once() {
+        let legacy = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let db_dir = data.path().join("nested");
+        seed_v1(legacy.path());
+
+        assert!(import_legacy(legacy.path(), &db_dir, "data.json").unwrap());
+        // 旧ファイルは残し、取り込み済みなら二度目は何もしない
+        assert!(legacy.path().join("data.json").exists());
+        assert!(!import_legacy(legacy.path(), &db_dir, "data.json").unwrap());
+
+        let store = Store::new(&db_dir, "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        assert_eq!(store.user_id(), "U123");
+    }
+
+    #[test]
+    fn accounts_are_stored_side_by_side() {
+        let dir = tempfile::tempdir().unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "bot".into(),
+            "botr".into(),
+        )
+        .unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            "broadcaster",
+            None,
+            "bc".into(),
+            "bcr".into(),
+        )
+        .unwrap();
+
+        let mut bot = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        let mut bc = Store::new(dir.path(), "data.json", "broadcaster", None).unwrap();
+        bot.set_user_id("U1".into()).unwrap();
+        bc.set_user_id("U2".into()).unwrap();
+
+        // それぞれの書き込みが相手のアカウントを上書きしない
+        let bot = read_account(dir.path(), DEFAULT_ACCOUNT);
+        let bc = read_account(dir.path(), "broadcaster");
+        assert_eq!(
+            (bot.access_token.as_str(), bot.user_id.as_str()),
+            ("bot", "U1")
+        );
+        assert_eq!(
+            (bc.access_token.as_str(), bc.user_id.as_str()),
+            ("bc", "U2")
+        );
+    }
+
+    #[test]
+    fn concurrent_writers_do_not_lose_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        for account in ["bot", "broadcaster"] {
+            save_tokens(
+                dir.path(),
+                "data.json",
+                account,
+                None,
+                "a".into(),
+                "r".into(),
+            )
+            .unwrap();
+        }
+
+        let writers: Vec<_> = ["bot", "broadcaster"]
+            .into_iter()
+            .map(|account| {
+                let path = dir.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let mut store = Store::new(&path, "data.json", account, None).unwrap();
+                    for i in 0..50 {
+                        store.set_user_id(format!("{account}-{i}")).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+
+        // ロックが無いと片方の読み直しと書き戻しの間にもう片方の更新が消える
+        assert_eq!(read_account(dir.path(), "bot").user_id, "bot-49");
+        assert_eq!(
+            read_account(dir.path(), "broadcaster").user_id,
+            "broadcaster-49"
+        );
+    }
+
+    #[test]
+    fn refresh_adopts_tokens_rotated_by_another_process() {
+        let dir = tempfile::tempdir().unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "old".into(),
+            "oldr".into(),
+        )
+        .unwrap();
+        let mut store = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+
+        // read-chat 実行中に別のプロセスがトークンを入れ替えた
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "new".into(),
+            "newr".into(),
+        )
+        .unwrap();
+
+        assert!(store.adopt_newer_tokens().unwrap());
+        assert_eq!(store.access_token(), "new");
+        assert_eq!(store.obj.refresh_token, "newr");
+        // 入れ替わっていなければ自分で更新する
+        assert!(!store.adopt_newer_tokens().unwrap());
+    }
+
+    #[test]
+    fn stale_writer_keeps_tokens_written_by_another_process() {
+        let dir = tempfile::tempdir().unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "old".into(),
+            "oldr".into(),
+        )
+        .unwrap();
+        let mut store = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "new".into(),
+            "newr".into(),
+        )
+        .unwrap();
+
+        // 古いトークンの検証結果は新しいトークンに付けない
+        store
+            .set_validation(3600, vec!["chat:read".into()], 1_000)
+            .unwrap();
+        store.set_user_id("U1".into()).unwrap();
+
+        let saved = read_account(dir.path(), DEFAULT_ACCOUNT);
+        assert_eq!(saved.refresh_token, "newr");
+        assert_eq!(saved.user_id, "U1");
+        assert_eq!(saved.validated_at, 0);
+        assert_eq!(store.access_token(), "new");
+    }
+
+    #[test]
+    fn missing_account_points_to_auth_code_with_account() {
+        let dir = tempfile::tempdir().unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "a".into(),
+            "r".into(),
+        )
+        .unwrap();
+
+        let err = Store::new(dir.path(), "data.json", "broadcaster", None)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err.to_string().contains("--account broadcaster"), "{err}");
+    }
+
+    #[test]
+    fn validation_is_kept_until_tokens_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "acc".into(),
+            "ref".into(),
+        )
+        .unwrap();
+        let mut store = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        assert_eq!(store.expires_at(), None);
+
+        store
+            .set_validation(3600, vec!["chat:read".into()], 1_000)
+            .unwrap();
+        assert_eq!(store.expires_at(), Some(4_600));
+        assert_eq!(store.obj.scopes, ["chat:read"]);
+
+        // re-auth replaces the token, so its lifetime is unknown again
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "acc2".into(),
+            "ref2".into(),
+        )
+        .unwrap();
+        let store = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        assert_eq!(store.expires_at(), None);
+    }
+
+    fn raw(dir: &Path) -> String {
+        std::fs::read_to_string(dir.join("data.json")).unwrap()
+    }
+
+    #[test]
+    fn encrypted_store_never_writes_tokens_in_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = Cipher::cheap("pass");
+
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            Some(&cipher),
+            "acc-secret".into(),
+            "ref-secret".into(),
+        )
+        .unwrap();
+        assert!(!raw(dir.path()).contains("secret"));
+
+        let mut store = Store::new(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            Some(Cipher::cheap("pass")),
+        )
+        .unwrap();
+        assert_eq!(store.access_token(), "acc-secret");
+        store.set_user_id("U123".into()).unwrap();
+        store
+            .set_validation(3600, vec!["chat:read".into()], 1_000)
+            .unwrap();
+        let text = raw(dir.path());
+        assert!(!text.contains("secret"));
+        assert!(!text.contains("U123"));
+        assert!(!text.contains("chat:read"));
+    }
+
+    #[test]
+    fn encrypted_store_needs_the_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = Cipher::cheap("pass");
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            Some(&cipher),
+            "a".into(),
+            "r".into(),
+        )
+        .unwrap();
+
+        let err = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("encrypt_store"), "{err}");
+        let err = Store::new(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            Some(Cipher::cheap("wrong")),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn opening_plaintext_with_cipher_encrypts_it() {
+        let dir = tempfile::tempdir().unwrap();
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "acc-secret".into(),
+            "r".into(),
+        )
+        .unwrap();
+        assert!(raw(dir.path()).contains("acc-secret"));
+
+        Store::new(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            Some(Cipher::cheap("pass")),
+        )
+        .unwrap();
+        assert!(!raw(dir.path()).contains("acc-secret"));
+    }
+
+    /// `dir` のどのファイルにも `secret` が平文で残っていない。
+    fn no_plaintext(dir: &Path, secret: &str) -> bool {
+        std::fs::read_dir(dir).unwrap().all(|e| {
+            !std::fs::read_to_string(e.unwrap().path())
+                .unwrap_or_default()
+                .contains(secret)
+        })
+    }
+
+    #[test]
+    fn encrypting_a_v1_store_leaves_no_plaintext_backup() {
+        let cipher = Cipher::cheap("pass");
+
+        // 平文のまま移行したあとで `tcyb store encrypt`
+        let dir = tempfile::tempdir().unwrap();
+        seed_v1(dir.path());
+        Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        assert!(!no_plaintext(dir.path(), "oldr"));
+        reseal(dir.path(), "data.json", None, Some(&cipher)).unwrap();
+        assert!(no_plaintext(dir.path(), "oldr"));
+        reseal(dir.path(), "data.json", Some(&cipher), None).unwrap();
+        let backup = std::fs::read_to_string(dir.path().join("data.v1.bak")).unwrap();
+        assert!(backup.contains("oldr"));
+
+        // v1 のストアを `encrypt_store = true` で初めて開く
+        let dir = tempfile::tempdir().unwrap();
+        seed_v1(dir.path());
+        Store::new(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            Some(Cipher::cheap("pass")),
+        )
+        .unwrap();
+        assert!(no_plaintext(dir.path(), "oldr"));
+    }
+
+    #[test]
+    fn reseal_migrates_both_ways() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = Cipher::cheap("pass");
+        save_tokens(
+            dir.path(),
+            "data.json",
+            DEFAULT_ACCOUNT,
+            None,
+            "acc-secret".into(),
+            "r".into(),
+        )
+        .unwrap();
+
+        reseal(dir.path(), "data.json", None, Some(&cipher)).unwrap();
+        assert!(!raw(dir.path()).contains("acc-secret"));
+
+        reseal(dir.path(), "data.json", Some(&cipher), None).unwrap();
+        let store = Store::new(dir.path(), "data.json", DEFAULT_ACCOUNT, None).unwrap();
+        assert_eq!(store.access_token(), "acc-secret");
+    }
 }
@@ -0,0 +1,169 @@
+//! 配信のオンライン/オフラインに応じた読み上げ制御。
+//!
+//! EventSub の `stream.online` / `stream.offline` 通知と、起動時の Helix Get Streams の
+//! 結果を `[stream_state]` の `action` に従って読み上げ側へ反映する。
+
+use crate::api;
+use crate::helix::HelixClient;
+use crate::settings::{StreamStateAction, StreamStateSettings};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 読み上げを止めるかどうかの共有フラグ。止めている間もチャットのログは出し続ける。
+/// 配信状態の連動（`muted`）と制御 API からの一時停止（`paused`）は別々に持ち、
+/// どちらかが立っていれば読み上げない。
+#[derive(Default)]
+pub struct ReadingGate {
+    muted: AtomicBool,
+    paused: AtomicBool,
+}
+
+impl ReadingGate {
+    pub fn is_muted(&self) -> bool {
+        self.is_stream_muted() || self.is_paused()
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::SeqCst);
+    }
+
+    pub fn is_stream_muted(&self) -> bool {
+        self.muted.load(Ordering::SeqCst)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+}
+
+/// 配信状態の変化を設定どおりに反映する。`chains` は `announce` の読み上げに使う。
+pub async fn apply(
+    settings: &StreamStateSettings,
+    online: bool,
+    reading: &ReadingGate,
+    address: &str,
+    chains: &[Vec<String>],
+) -> Result<(), vstc::VstcError> {
+    match settings.action {
+        StreamStateAction::None => {}
+        StreamStateAction::Pause => {
+            let (label, ops) = if online {
+                ("resume", &settings.resume_operations)
+            } else {
+                ("pause", &settings.pause_operations)
+            };
+            info!("stream state: send {} to vstreamer", label);
+            vstc::process_command(address, ops, String::new(), None, None, None).await?;
+        }
+        StreamStateAction::Mute => {
+            reading.set_muted(!online);
+            if online {
+                info!("stream state: reading resumed");
+            } else {
+                info!("stream state: reading muted (chat is still logged)");
+            }
+        }
+        StreamStateAction::Announce => {
+            if online {
+                info!("stream state: announce {:?}", settings.online_text);
+                crate::speech::speak(address, chains, settings.online_text.clone()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 起動時に Helix で現在の配信状態を調べてログに出し、オフラインなら停止側を反映する。
+/// 起動済みの配信に「配信開始」を告げても仕方ないので、オンライン時は何もしない。
+/// 問い合わせに失敗しても読み上げは止めず、警告だけ残す。配信中ならその配信の ID を返す。
+pub async fn apply_initial(
+    settings: &StreamStateSettings,
+    reading: &ReadingGate,
+    helix: &mut HelixClient,
+    user_id: &str,
+    address: &str,
+    chains: &[Vec<String>],
+) -> Option<String> {
+    match api::get_streams(helix, user_id).await {
+        Ok(streams) => {
+            if let Some(s) = streams.data.into_iter().next() {
+                info!(
+                    "stream state at startup: live since {} ({:?}, id {})",
+                    s.started_at, s.title, s.id
+                );
+                return Some(s.id);
+            }
+            info!("stream state at startup: offline");
+        }
+        Err(err) => {
+            warn!("stream state at startup: failed to query streams: {}", err);
+            return None;
+        }
+    }
+    if let Err(err) = apply(settings, false, reading, address, chains).await {
+        warn!("stream state at startup: vstc error {}: ignore it.", err);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(action: StreamStateAction) -> StreamStateSettings {
+        StreamStateSettings {
+            action,
+            ..StreamStateSettings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn mute_follows_online_state() {
+        let gate = ReadingGate::default();
+        let s = settings(StreamStateAction::Mute);
+
+        apply(&s, false, &gate, "http://localhost:1", &[])
+            .await
+            .unwrap();
+        assert!(gate.is_muted());
+
+        apply(&s, true, &gate, "http://localhost:1", &[])
+            .await
+            .unwrap();
+        assert!(!gate.is_muted());
+    }
+
+    #[tokio::test]
+    async fn going_online_keeps_a_manual_pause() {
+        let gate = ReadingGate::default();
+        gate.set_paused(true);
+
+        apply(
+            &settings(StreamStateAction::Mute),
+            true,
+            &gate,
+            "http://localhost:1",
+            &[],
+        )
+        .await
+        .unwrap();
+        assert!(!gate.is_stream_muted());
+        assert!(gate.is_muted());
+    }
+
+    #[tokio::test]
+    async fn none_and_offline_announce_touch_nothing() {
+        let gate = ReadingGate::default();
+        for action in [StreamStateAction::None, StreamStateAction::Announce] {
+            // vstc へ送らない経路なので、到達不能なアドレスでも成功する。
+            apply(&settings(action), false, &gate, "http://localhost:1", &[])
+                .await
+                .unwrap();
+            assert!(!gate.is_muted());
+        }
+    }
+}
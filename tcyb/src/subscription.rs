@@ -0,0 +1,148 @@
+//! `tcyb eventsub ...` — Helix に登録済みの EventSub 購読の確認と掃除。
+//!
+//! `sub_event` が 409（重複）や 403（スコープ不足）で失敗したとき、
+//! 何が残っているかを調べて孤立した購読を消すためのサブコマンド群。
+
+use crate::api::{self, EventSubSubscriptionInfo};
+use crate::helix::HelixClient;
+use anyhow::bail;
+use log::info;
+use std::collections::BTreeMap;
+
+/// 購読が正常に配信を受けている状態。これ以外（切断・失効など）は無効扱い。
+const STATUS_ENABLED: &str = "enabled";
+
+fn transport_label(sub: &EventSubSubscriptionInfo) -> String {
+    let target = match sub.transport.method.as_str() {
+        "websocket" => sub.transport.session_id.as_deref(),
+        _ => sub.transport.callback.as_deref(),
+    };
+    match target {
+        Some(t) => format!("{}:{}", sub.transport.method, t),
+        None => sub.transport.method.clone(),
+    }
+}
+
+fn format_row(sub: &EventSubSubscriptionInfo) -> String {
+    format!(
+        "{:<36}  {:<28}  {:>3}  {:<28}  {:>4}  {}",
+        sub.id,
+        sub.type_,
+        sub.version,
+        sub.status,
+        sub.cost,
+        transport_label(sub)
+    )
+}
+
+pub async fn list(helix: &mut HelixClient) -> anyhow::Result<()> {
+    let (subs, totals) = api::get_event_subs(helix).await?;
+    println!(
+        "{:<36}  {:<28}  {:>3}  {:<28}  {:>4}  TRANSPORT",
+        "ID", "TYPE", "VER", "STATUS", "COST"
+    );
+    for sub in &subs {
+        println!("{}", format_row(sub));
+    }
+    println!(
+        "{} subscriptions, cost {}/{}",
+        totals.total, totals.total_cost, totals.max_total_cost
+    );
+    Ok(())
+}
+
+pub async fn cost(helix: &mut HelixClient) -> anyhow::Result<()> {
+    let (subs, totals) = api::get_event_subs(helix).await?;
+    println!("total: {}", totals.total);
+    println!("total_cost: {}", totals.total_cost);
+    println!("max_total_cost: {}", totals.max_total_cost);
+    for (type_, (count, cost)) in cost_by_type(&subs) {
+        println!("  {type_}: {count} subscriptions, cost {cost}");
+    }
+    Ok(())
+}
+
+fn cost_by_type(subs: &[EventSubSubscriptionInfo]) -> BTreeMap<&str, (usize, i64)> {
+    let mut by_type: BTreeMap<&str, (usize, i64)> = BTreeMap::new();
+    for sub in subs {
+        let entry = by_type.entry(sub.type_.as_str()).or_default();
+        entry.0 += 1;
+        entry.1 += sub.cost;
+    }
+    by_type
+}
+
+/// `id` を 1 件消すか、`all_disabled` なら `enabled` 以外の購読をすべて消す。
+pub async fn delete(
+    helix: &mut HelixClient,
+    id: Option<&str>,
+    all_disabled: bool,
+) -> anyhow::Result<()> {
+    let ids: Vec<String> = if all_disabled {
+        let (subs, _) = api::get_event_subs(helix).await?;
+        subs.into_iter()
+            .filter(|s| s.status != STATUS_ENABLED)
+            .map(|s| {
+                info!("delete {} ({}, {})", s.id, s.type_, s.status);
+                s.id
+            })
+            .collect()
+    } else {
+        match id {
+            Some(id) => vec![id.to_string()],
+            None => bail!("subscription id or --all-disabled is required"),
+        }
+    };
+    for id in &ids {
+        api::delete_event_sub(helix, id).await?;
+        println!("deleted {id}");
+    }
+    println!("{} subscriptions deleted", ids.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::EventSubTransportInfo;
+
+    fn sub(type_: &str, cost: i64, method: &str) -> EventSubSubscriptionInfo {
+        EventSubSubscriptionInfo {
+            id: String::from("abc"),
+            status: String::from(STATUS_ENABLED),
+            type_: type_.to_string(),
+            version: String::from("1"),
+            cost,
+            created_at: String::new(),
+            transport: EventSubTransportInfo {
+                method: method.to_string(),
+                session_id: Some(String::from("sess")),
+                callback: Some(String::from("https://example.com/cb")),
+            },
+        }
+    }
+
+    #[test]
+    fn transport_label_picks_target_by_method() {
+        assert_eq!(
+            transport_label(&sub("stream.online", 0, "websocket")),
+            "websocket:sess"
+        );
+        assert_eq!(
+            transport_label(&sub("stream.online", 0, "webhook")),
+            "webhook:https://example.com/cb"
+        );
+    }
+
+    #[test]
+    fn cost_by_type_sums_per_type() {
+        let subs = vec![
+            sub("channel.follow", 0, "websocket"),
+            sub("stream.online", 1, "websocket"),
+            sub("stream.online", 1, "websocket"),
+        ];
+        let by_type = cost_by_type(&subs);
+        assert_eq!(by_type["channel.follow"], (1, 0));
+        assert_eq!(by_type["stream.online"], (2, 2));
+    }
+}
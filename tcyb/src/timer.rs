@@ -0,0 +1,205 @@
+//! read-chat の定期投稿（`[[timers]]`）と IRC の送信数の制限。
+//!
+//! 各タイマーは `interval_secs` ごとに `message` をチャットへ投稿する。前回の投稿から
+//! 数えた他人のチャットが `min_chat_messages` に満たなければその回は見送り、次の周期で
+//! 見直す。状態は IRC の張り直しをまたいで持ち越すので、再接続で周期は最初に戻らない。
+
+use crate::settings::TimerSettings;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Twitch がモデレーターでないユーザーに許す PRIVMSG の数と、その数える幅。
+/// 超えると 30 分ほど送れなくなるので、手前で止める。
+const OUTBOUND_MAX: usize = 20;
+const OUTBOUND_WINDOW_SECS: u64 = 30;
+
+/// 直近 `window` に送った数を数える。
+struct OutboundLimit {
+    sent: VecDeque<Instant>,
+    max: usize,
+    window: Duration,
+}
+
+impl Default for OutboundLimit {
+    fn default() -> Self {
+        Self::new(OUTBOUND_MAX, Duration::from_secs(OUTBOUND_WINDOW_SECS))
+    }
+}
+
+impl OutboundLimit {
+    fn new(max: usize, window: Duration) -> Self {
+        Self {
+            sent: VecDeque::new(),
+            max,
+            window,
+        }
+    }
+
+    /// 今送れるなら 1 通として数える。送れなければ枠が空くまでの時間を返す。
+    fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        while self
+            .sent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= self.window)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() < self.max {
+            self.sent.push_back(now);
+            return Ok(());
+        }
+        let oldest = self.sent.front().copied().unwrap_or(now);
+        Err(self.window.saturating_sub(now.duration_since(oldest)))
+    }
+}
+
+struct TimerState {
+    settings: TimerSettings,
+    last_run: Instant,
+    chats: u64,
+}
+
+/// タイマーごとの前回の投稿時刻と、それ以降のチャット数。
+#[derive(Default)]
+struct Timers {
+    states: Vec<TimerState>,
+}
+
+impl Timers {
+    /// 設定に並びを合わせる。設定の変わらないタイマーは状態を引き継ぎ、新しいタイマーは
+    /// `now` から数え始める。
+    fn sync(&mut self, timers: &[TimerSettings], now: Instant) {
+        let mut old = std::mem::take(&mut self.states);
+        self.states = timers
+            .iter()
+            .map(
+                |settings| match old.iter().position(|s| &s.settings == settings) {
+                    Some(i) => old.swap_remove(i),
+                    None => TimerState {
+                        settings: settings.clone(),
+                        last_run: now,
+                        chats: 0,
+                    },
+                },
+            )
+            .collect();
+    }
+
+    fn on_chat(&mut self) {
+        for state in &mut self.states {
+            state.chats += 1;
+        }
+    }
+
+    /// 周期の来たタイマーのうち、チャット数の条件を満たすもの。周期の来たタイマーは
+    /// 投稿しなくても次の周期を数え直す。
+    fn due(&mut self, now: Instant) -> Vec<TimerSettings> {
+        let mut due = Vec::new();
+        for state in &mut self.states {
+            let interval = Duration::from_secs(state.settings.interval_secs);
+            if now.duration_since(state.last_run) < interval {
+                continue;
+            }
+            state.last_run = now;
+            if state.chats >= state.settings.min_chat_messages {
+                state.chats = 0;
+                due.push(state.settings.clone());
+            }
+        }
+        due
+    }
+}
+
+/// IRC タスクが張り直しをまたいで使う送信の状態。
+#[derive(Default)]
+pub struct Outbox {
+    limit: Mutex<OutboundLimit>,
+    timers: Mutex<Timers>,
+}
+
+impl Outbox {
+    pub fn try_send(&self, now: Instant) -> Result<(), Duration> {
+        self.limit.lock().unwrap().try_acquire(now)
+    }
+
+    /// 他人のチャットを 1 件数える。
+    pub fn on_chat(&self) {
+        self.timers.lock().unwrap().on_chat();
+    }
+
+    /// `timers`（実行中に読み直した設定）のうち、`now` に投稿するもの。
+    pub fn due(&self, timers: &[TimerSettings], now: Instant) -> Vec<TimerSettings> {
+        let mut state = self.timers.lock().unwrap();
+        state.sync(timers, now);
+        state.due(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer(message: &str, interval_secs: u64, min_chat_messages: u64) -> TimerSettings {
+        TimerSettings {
+            message: message.to_string(),
+            interval_secs,
+            min_chat_messages,
+            speak: false,
+        }
+    }
+
+    #[test]
+    fn limit_refuses_until_the_window_slides() {
+        let start = Instant::now();
+        let mut limit = OutboundLimit::new(2, Duration::from_secs(30));
+        assert!(limit.try_acquire(start).is_ok());
+        assert!(limit.try_acquire(start + Duration::from_secs(10)).is_ok());
+        assert_eq!(
+            limit.try_acquire(start + Duration::from_secs(20)),
+            Err(Duration::from_secs(10))
+        );
+        assert!(limit.try_acquire(start + Duration::from_secs(30)).is_ok());
+    }
+
+    #[test]
+    fn timer_waits_for_interval_and_chat_count() {
+        let start = Instant::now();
+        let outbox = Outbox::default();
+        let timers = vec![timer("discord", 60, 2)];
+        assert!(outbox.due(&timers, start).is_empty());
+        outbox.on_chat();
+        // 周期は来たがチャットが 1 件だけなので見送り、次の周期を待つ
+        assert!(outbox
+            .due(&timers, start + Duration::from_secs(60))
+            .is_empty());
+        outbox.on_chat();
+        assert!(outbox
+            .due(&timers, start + Duration::from_secs(90))
+            .is_empty());
+        assert_eq!(
+            outbox.due(&timers, start + Duration::from_secs(120)),
+            timers
+        );
+        // 投稿したらチャット数は数え直し
+        assert!(outbox
+            .due(&timers, start + Duration::from_secs(180))
+            .is_empty());
+    }
+
+    #[test]
+    fn reload_keeps_state_of_unchanged_timers() {
+        let start = Instant::now();
+        let outbox = Outbox::default();
+        let kept = timer("kept", 60, 0);
+        outbox.due(std::slice::from_ref(&kept), start);
+        let reloaded = vec![timer("added", 60, 0), kept.clone()];
+        assert!(outbox
+            .due(&reloaded, start + Duration::from_secs(30))
+            .is_empty());
+        assert_eq!(
+            outbox.due(&reloaded, start + Duration::from_secs(60)),
+            vec![kept]
+        );
+    }
+}
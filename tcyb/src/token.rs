@@ -0,0 +1,236 @@
+//! トークンの検証と先回りの更新、`tcyb token status`。
+//!
+//! Twitch はアプリに 1 時間ごとの `/oauth2/validate` を求めている。read-chat は起動時と
+//! その後 1 時間おきに検証し、失効が近ければ接続が失敗する前に更新しておく。
+
+use crate::api::ValidatedToken;
+use crate::helix::{HelixClient, HelixError, SharedHelix};
+use crate::settings::{Mitigation, Settings};
+use log::{info, warn};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Twitch が求める検証間隔。
+const VALIDATE_INTERVAL_SECS: u64 = 3600;
+/// 残りがこれを切ったら失効を待たずに更新する。
+const REFRESH_MARGIN_SECS: u64 = 600;
+/// 検証が通信エラーで失敗したときの再試行間隔。
+const VALIDATE_RETRY_SECS: u64 = 60;
+
+/// `account` が受け持つ機能に要るスコープ。`(scope, 機能)` の組で返す。
+/// どの機能をどのアカウントが受け持つかは `[accounts]` に従う。
+pub fn required_scopes(settings: &Settings, account: &str) -> Vec<(&'static str, &'static str)> {
+    let accounts = &settings.accounts;
+    let mut scopes = vec![
+        ("chat:read", "read-chat", accounts.chat.as_str()),
+        ("chat:edit", "read-chat の翻訳返信", &accounts.chat),
+        ("user:write:chat", "say", &accounts.chat),
+        ("moderator:read:followers", "フォロー挨拶", &accounts.events),
+        (
+            "moderator:manage:banned_users",
+            "ban-bots",
+            &accounts.moderation,
+        ),
+        (
+            "moderator:read:chatters",
+            "show-chatters",
+            &accounts.moderation,
+        ),
+        ("user:read:follows", "show-followings", &accounts.lookup),
+    ];
+    match settings.follow_storm.mitigation {
+        Mitigation::None => {}
+        Mitigation::FollowersOnly => scopes.push((
+            "moderator:manage:chat_settings",
+            "follow_storm.mitigation = \"followers_only\"",
+            &accounts.events,
+        )),
+        Mitigation::ShieldMode => scopes.push((
+            "moderator:manage:shield_mode",
+            "follow_storm.mitigation = \"shield_mode\"",
+            &accounts.events,
+        )),
+    }
+    scopes
+        .into_iter()
+        .filter(|(_, _, a)| *a == account)
+        .map(|(scope, feature, _)| (scope, feature))
+        .collect()
+}
+
+/// `granted` に無い必要スコープ。
+pub fn missing_scopes(
+    settings: &Settings,
+    account: &str,
+    granted: &[String],
+) -> Vec<(&'static str, &'static str)> {
+    required_scopes(settings, account)
+        .into_iter()
+        .filter(|(scope, _)| !granted.iter().any(|g| g == scope))
+        .collect()
+}
+
+/// 次に検証するまでの待ち時間。失効が 1 時間以内なら更新すべき時刻に合わせる。
+/// 更新に失敗し続けても空回りしないよう、最短でも再試行間隔は空ける。
+fn next_check(remaining_secs: Option<u64>) -> Duration {
+    let secs = match remaining_secs {
+        Some(r) => r
+            .saturating_sub(REFRESH_MARGIN_SECS)
+            .clamp(VALIDATE_RETRY_SECS, VALIDATE_INTERVAL_SECS),
+        None => VALIDATE_INTERVAL_SECS,
+    };
+    Duration::from_secs(secs)
+}
+
+fn format_lifetime(secs: u64) -> String {
+    format!(
+        "{}h {:02}m {:02}s",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// 検証し、失効が近ければ更新して検証し直す。
+async fn validate_and_refresh(helix: &mut HelixClient) -> Result<ValidatedToken, HelixError> {
+    let token = helix.validate().await?;
+    if helix
+        .remaining_secs()
+        .is_some_and(|r| r < REFRESH_MARGIN_SECS)
+    {
+        info!("token expires soon: refresh it ahead of time");
+        helix.refresh().await?;
+        return helix.validate().await;
+    }
+    Ok(token)
+}
+
+/// read-chat の起動時検証。`account` に足りないスコープを警告し、次の検証までの待ち時間を返す。
+pub async fn check_at_startup(
+    helix: &mut HelixClient,
+    settings: &Settings,
+    account: &str,
+) -> Result<Duration, HelixError> {
+    let token = validate_and_refresh(helix).await?;
+    let remaining = helix.remaining_secs();
+    if let Some(r) = remaining {
+        info!("token valid for {}", format_lifetime(r));
+    }
+    for (scope, feature) in missing_scopes(settings, account, &token.scopes) {
+        warn!(
+            "token of account {} lacks scope {} (needed for {}); re-authenticate via `tcyb auth-code --account {}`",
+            account, scope, feature, account
+        );
+    }
+    Ok(next_check(remaining))
+}
+
+/// 1 時間おき（失効が近ければそれより早く）に検証を続ける。失敗しても止まらない。
+pub async fn validate_loop(helix: SharedHelix, first_wait: Duration) {
+    let mut wait = first_wait;
+    loop {
+        sleep(wait).await;
+        let mut helix = helix.lock().await;
+        wait = match validate_and_refresh(&mut helix).await {
+            Ok(_) => next_check(helix.remaining_secs()),
+            Err(err) => {
+                warn!("token validation failed: {}", err);
+                Duration::from_secs(VALIDATE_RETRY_SECS)
+            }
+        };
+    }
+}
+
+pub async fn status(
+    helix: &mut HelixClient,
+    settings: &Settings,
+    account: &str,
+) -> anyhow::Result<()> {
+    let token = helix.validate().await?;
+    println!("account: {account}");
+    println!("login: {}", token.login.as_deref().unwrap_or("-"));
+    println!("user_id: {}", token.user_id.as_deref().unwrap_or("-"));
+    println!("client_id: {}", token.client_id);
+    println!("scopes: {}", token.scopes.join(" "));
+    match helix.remaining_secs() {
+        Some(r) => println!("expires in: {}", format_lifetime(r)),
+        None => println!("expires in: never"),
+    }
+    for (scope, feature) in missing_scopes(settings, account, &token.scopes) {
+        println!("warning: missing scope {scope} (needed for {feature})");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::FollowStormSettings;
+    use crate::store::DEFAULT_ACCOUNT;
+
+    fn settings(mitigation: Mitigation) -> Settings {
+        Settings {
+            follow_storm: FollowStormSettings {
+                mitigation,
+                ..FollowStormSettings::default()
+            },
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn mitigation_adds_its_scope() {
+        let granted: Vec<String> = required_scopes(&settings(Mitigation::None), DEFAULT_ACCOUNT)
+            .into_iter()
+            .map(|(s, _)| s.to_string())
+            .collect();
+        assert!(missing_scopes(&settings(Mitigation::None), DEFAULT_ACCOUNT, &granted).is_empty());
+        assert_eq!(
+            missing_scopes(&settings(Mitigation::ShieldMode), DEFAULT_ACCOUNT, &granted)
+                .into_iter()
+                .map(|(s, _)| s)
+                .collect::<Vec<_>>(),
+            vec!["moderator:manage:shield_mode"]
+        );
+    }
+
+    #[test]
+    fn scopes_follow_the_account_of_each_feature() {
+        let mut s = settings(Mitigation::FollowersOnly);
+        s.accounts.events = String::from("broadcaster");
+        s.accounts.lookup = String::from("broadcaster");
+        let scopes = |account| -> Vec<&str> {
+            required_scopes(&s, account)
+                .into_iter()
+                .map(|(scope, _)| scope)
+                .collect()
+        };
+        assert_eq!(
+            scopes("broadcaster"),
+            vec![
+                "moderator:read:followers",
+                "user:read:follows",
+                "moderator:manage:chat_settings"
+            ]
+        );
+        assert!(!scopes(DEFAULT_ACCOUNT).contains(&"moderator:read:followers"));
+        assert!(scopes(DEFAULT_ACCOUNT).contains(&"chat:read"));
+    }
+
+    #[test]
+    fn next_check_is_hourly_for_long_lived_tokens() {
+        assert_eq!(next_check(Some(14_000)), Duration::from_secs(3600));
+        assert_eq!(next_check(None), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn next_check_targets_refresh_before_expiry() {
+        assert_eq!(next_check(Some(1_000)), Duration::from_secs(400));
+        assert_eq!(next_check(Some(100)), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn lifetime_is_human_readable() {
+        assert_eq!(format_lifetime(3 * 3600 + 2 * 60 + 5), "3h 02m 05s");
+    }
+}
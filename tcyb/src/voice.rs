@@ -0,0 +1,157 @@
+//! 発言者ごとの声の割り当て。
+//!
+//! チャットの発言者から `[voices]` の声を選び、送る直前の `tts` route の `i`（話者）と
+//! `spd` / `pit` を書き換える。`pool` からの選択はログイン名のハッシュで決まるので、
+//! 同じ人は再起動をまたいでも同じ声になる。
+
+use crate::settings::{Voice, VoiceSettings};
+use vstreamer_protos::{Operation, OperationRoute};
+
+/// バッジから決まる発言者の役割。並びは声を選ぶときの優先順。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Broadcaster,
+    Moderator,
+    Vip,
+    Subscriber,
+}
+
+/// IRC の `badges` タグ（`broadcaster/1,subscriber/12` など）から役割を拾う。
+/// `founder` はサブスクライバーとして扱う。
+pub fn roles_from_badges(badges: &str) -> Vec<Role> {
+    let mut roles: Vec<Role> = badges
+        .split(',')
+        .filter_map(
+            |badge| match badge.split_once('/').map_or(badge, |(b, _)| b) {
+                "broadcaster" => Some(Role::Broadcaster),
+                "moderator" => Some(Role::Moderator),
+                "vip" => Some(Role::Vip),
+                "subscriber" | "founder" => Some(Role::Subscriber),
+                _ => None,
+            },
+        )
+        .collect();
+    roles.sort();
+    roles.dedup();
+    roles
+}
+
+/// FNV-1a。`DefaultHasher` は Rust の版で値が変わりうるので使わない。
+fn stable_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// `login` の声を `users` → `roles` → `pool` の順に選ぶ。どれも無ければ `None`。
+pub fn voice_for(settings: &VoiceSettings, login: &str, roles: &[Role]) -> Option<Voice> {
+    let login = login.to_lowercase();
+    if let Some(voice) = settings.users.get(&login) {
+        return Some(voice.clone());
+    }
+    let by_role = roles.iter().find_map(|role| match role {
+        Role::Broadcaster => settings.roles.broadcaster.as_ref(),
+        Role::Moderator => settings.roles.moderator.as_ref(),
+        Role::Vip => settings.roles.vip.as_ref(),
+        Role::Subscriber => settings.roles.subscriber.as_ref(),
+    });
+    if let Some(voice) = by_role {
+        return Some(voice.clone());
+    }
+    if settings.pool.is_empty() {
+        return None;
+    }
+    let index = stable_hash(&login) % settings.pool.len() as u64;
+    Some(Voice {
+        speaker: settings.pool[index as usize],
+        ..Voice::default()
+    })
+}
+
+/// 全 chain の `tts` route に `voice` を当てる。他の route には触らない。
+pub fn apply_voice(chains: &mut [Vec<OperationRoute>], voice: &Voice) {
+    let tts = Operation::Tts as i32;
+    for route in chains.iter_mut().flatten().filter(|r| r.operation == tts) {
+        route
+            .queries
+            .insert(String::from("i"), voice.speaker.to_string());
+        if let Some(spd) = voice.spd {
+            route.queries.insert(String::from("spd"), spd.to_string());
+        }
+        if let Some(pit) = voice.pit {
+            route.queries.insert(String::from("pit"), pit.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::RoleVoices;
+
+    fn voice(speaker: u32) -> Voice {
+        Voice {
+            speaker,
+            ..Voice::default()
+        }
+    }
+
+    fn settings() -> VoiceSettings {
+        VoiceSettings {
+            users: [(String::from("alice"), voice(5))].into(),
+            roles: RoleVoices {
+                moderator: Some(voice(3)),
+                subscriber: Some(voice(4)),
+                ..RoleVoices::default()
+            },
+            pool: vec![10, 11, 12],
+        }
+    }
+
+    #[test]
+    fn user_mapping_wins_over_role() {
+        let v = voice_for(&settings(), "Alice", &[Role::Moderator]).unwrap();
+        assert_eq!(v.speaker, 5);
+    }
+
+    #[test]
+    fn highest_role_with_a_voice_is_used() {
+        let roles = roles_from_badges("subscriber/12,moderator/1,broadcaster/1");
+        assert_eq!(
+            roles,
+            vec![Role::Broadcaster, Role::Moderator, Role::Subscriber]
+        );
+        // broadcaster には声が無いので moderator の声になる
+        assert_eq!(voice_for(&settings(), "bob", &roles).unwrap().speaker, 3);
+    }
+
+    #[test]
+    fn pool_choice_is_stable_per_login() {
+        let s = settings();
+        let first = voice_for(&s, "carol", &[]).unwrap().speaker;
+        assert!(s.pool.contains(&first));
+        assert_eq!(voice_for(&s, "carol", &[]).unwrap().speaker, first);
+        assert_eq!(voice_for(&VoiceSettings::default(), "carol", &[]), None);
+    }
+
+    #[test]
+    fn only_tts_routes_are_rewritten() {
+        let mut chains = vec![vec![
+            vstc::parse_route("o:/transl?t=ja").unwrap(),
+            vstc::parse_route("o:/tts?i=1&spd=1.1&pit=-0.05").unwrap(),
+        ]];
+        apply_voice(
+            &mut chains,
+            &Voice {
+                speaker: 7,
+                spd: Some(1.3),
+                pit: None,
+            },
+        );
+        assert_eq!(chains[0][0].queries.get("i"), None);
+        let tts = &chains[0][1].queries;
+        assert_eq!(tts["i"], "7");
+        assert_eq!(tts["spd"], "1.3");
+        assert_eq!(tts["pit"], "-0.05");
+    }
+}
@@ -1,34 +1,48 @@
 use std::time::Duration;
 
-use crate::settings::Settings;
-use crate::store::{Store, StoreError};
-use crate::{eventsub::sub_event_client_loop, irc::read_chat_client_loop};
+use crate::chatlog::ChatLog;
+use crate::control::Control;
+use crate::eventsub::{sub_event_client_loop, EventContext};
+use crate::followstorm::FollowStorm;
+use crate::greeting::Greeter;
+use crate::helix::{HelixClient, SharedHelix};
+use crate::irc::{read_chat_client_loop, ChatContext};
+use crate::overlay::Overlay;
+use crate::settings::{ConfigSource, Settings};
+use crate::speech::SpeechEvent;
+use crate::store::StoreError;
+use crate::stream::ReadingGate;
+use crate::timer::Outbox;
 use anyhow::bail;
 use log::warn;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tracing::Instrument;
 
-const IRC_CONNECT_ADDR: &str = "wss://irc-ws.chat.twitch.tv:443";
+pub const IRC_CONNECT_ADDR: &str = "wss://irc-ws.chat.twitch.tv:443";
 const IRC_TIMEOUT_SECS: u64 = 180;
 const EVENT_CONNECT_ADDR: &str = "wss://eventsub.wss.twitch.tv:443/ws";
 const EVENT_TIMEOUT_SECS: u64 = 30;
 const MAX_TOKEN_REFRESH_RETRIES: u32 = 5;
 const TOKEN_REFRESH_INITIAL_BACKOFF_SECS: u64 = 5;
 const TOKEN_REFRESH_MAX_BACKOFF_SECS: u64 = 300;
+/// 配信ごとの挨拶済みの一覧（`db_dir` に置く）。
+const GREETINGS_FILE: &str = "greetings.json";
 
-async fn refresh_tokens_with_backoff(
-    store: &mut Store,
-    client_id: &str,
-    client_secret: &str,
-) -> anyhow::Result<()> {
+/// EventSub 側のタスクも同じクライアントを使うため、ロックは試行ごとに取り直す。
+async fn refresh_tokens_with_backoff(helix: &SharedHelix) -> anyhow::Result<()> {
     let mut attempt = 0u32;
     let mut backoff = TOKEN_REFRESH_INITIAL_BACKOFF_SECS;
     loop {
-        match store
-            .update_tokens(client_id, client_secret)
-            .instrument(tracing::info_span!("token_refresh"))
+        let result = helix
+            .lock()
             .await
-        {
+            .refresh()
+            .instrument(tracing::info_span!("token_refresh"))
+            .await;
+        match result {
             Ok(_) => return Ok(()),
             Err(e) => {
                 let is_permanent = matches!(
@@ -63,85 +77,184 @@ async fn refresh_tokens_with_backoff(
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-pub async fn yomiage(settings: &Settings) -> anyhow::Result<()> {
-    let irc_url = url::Url::parse(IRC_CONNECT_ADDR)?;
-    let event_url = url::Url::parse(EVENT_CONNECT_ADDR)?;
-    let mut store = {
+/// 片方のセッションが終わったときの後始末。エラー終了ならトークンを更新してから
+/// もう片方を止め、次のループで両方を張り直す。
+async fn on_session_end<E: std::fmt::Display>(
+    r: Result<Result<(), E>, tokio::task::JoinError>,
+    other: &tokio::task::AbortHandle,
+    helix: &SharedHelix,
+    control: &Control,
+    source: &'static str,
+) -> anyhow::Result<()> {
+    match r {
+        Ok(Ok(())) => warn!("connection closed."),
+        Ok(Err(e)) => {
+            warn!("error {}: try to reconnect.", e);
+            control.record_error(source, &e);
+            refresh_tokens_with_backoff(helix).await?;
+        }
+        Err(e) => bail!(e),
+    }
+    other.abort();
+    Ok(())
+}
+
+/// ストアから `account` を開いてトークンを検証し、定期検証のタスクを起こす。
+async fn open_helix(
+    settings: &Settings,
+    account: &str,
+) -> anyhow::Result<(SharedHelix, JoinHandle<()>)> {
+    let mut helix = {
         let _span = tracing::info_span!("store_new").entered();
-        Store::new(&settings.db_dir, &settings.db_name)?
+        HelixClient::from_settings(settings, account)?
+    };
+    let first_validate_wait = crate::token::check_at_startup(&mut helix, settings, account)
+        .instrument(tracing::info_span!("token_validate"))
+        .await?;
+    let helix: SharedHelix = Arc::new(tokio::sync::Mutex::new(helix));
+    let validate_t = tokio::spawn(crate::token::validate_loop(
+        helix.clone(),
+        first_validate_wait,
+    ));
+    Ok((helix, validate_t))
+}
+
+/// IRC 用のクライアントと、そのトークンの持ち主の login。EventSub と同じアカウントなら
+/// クライアントを共有する。同じアカウントを別の `Store` で開くと、片方の更新でもう片方の
+/// refresh token が古くなる。IRC の NICK と自分の発言の読み飛ばしには、設定の `username`
+/// ではなくこの login を使う。
+async fn open_chat_helix(
+    settings: &Settings,
+    events_helix: &SharedHelix,
+) -> anyhow::Result<(SharedHelix, String, Option<JoinHandle<()>>)> {
+    let (helix, validate_t) = if settings.accounts.chat == settings.accounts.events {
+        (events_helix.clone(), None)
+    } else {
+        let (helix, validate_t) = open_helix(settings, &settings.accounts.chat).await?;
+        (helix, Some(validate_t))
     };
-    let user_id = store
-        .user_id(&settings.username, &settings.client_id)
+    let login = helix.lock().await.login().await?;
+    Ok((helix, login, validate_t))
+}
+
+/// 起動時の配信状態を反映する。配信中ならその配信の挨拶済みの記録を引き継ぐ。
+async fn apply_startup_stream_state(
+    settings: &Settings,
+    reading: &ReadingGate,
+    helix: &SharedHelix,
+    user_id: &str,
+    greeter: &Greeter,
+) {
+    let stream_id = crate::stream::apply_initial(
+        &settings.stream_state,
+        reading,
+        &mut *helix.lock().await,
+        user_id,
+        &settings.speech_address,
+        &settings.chains(SpeechEvent::Chat),
+    )
+    .instrument(tracing::info_span!("stream_state_fetch"))
+    .await;
+    if let Some(stream_id) = stream_id {
+        greeter.start_stream(&stream_id);
+    }
+}
+
+pub async fn yomiage(settings: &Settings, source: ConfigSource) -> anyhow::Result<()> {
+    let irc_url = url::Url::parse(IRC_CONNECT_ADDR)?;
+    let event_url = url::Url::parse(EVENT_CONNECT_ADDR)?;
+    let (events_helix, events_validate_t) = open_helix(settings, &settings.accounts.events).await?;
+    let (chat_helix, chat_login, chat_validate_t) =
+        open_chat_helix(settings, &events_helix).await?;
+    let user_id = events_helix
+        .lock()
+        .await
+        .user_id()
         .instrument(tracing::info_span!("user_id_fetch"))
         .await?;
+    let follow_storm = FollowStorm::shared(&settings.follow_storm);
+    let reading = Arc::new(ReadingGate::default());
+    let chat_log = Arc::new(ChatLog::new(settings.chat_log_dir()));
+    // 定期投稿の周期と送信数は IRC の張り直しをまたいで数える
+    let outbox = Arc::new(Outbox::default());
+    let greeter = Arc::new(Greeter::load(settings.db_dir.join(GREETINGS_FILE)));
+    apply_startup_stream_state(settings, &reading, &events_helix, &user_id, &greeter).await;
+    let (live_tx, live) = watch::channel(Arc::new(settings.clone()));
+    let reload_t = tokio::spawn(crate::reload::watch_config(source, live_tx));
+    let control = Arc::new(Control::default());
+    let control_t = crate::control::start(
+        &settings.control,
+        control.clone(),
+        reading.clone(),
+        live.clone(),
+    )
+    .await?;
+    let overlay = Arc::new(Overlay::default());
+    let overlay_t = crate::overlay::start(&settings.overlay, overlay.clone()).await?;
+    // 終了時に止める常駐タスク
+    let background: Vec<_> = [Some(&events_validate_t), Some(&reload_t)]
+        .into_iter()
+        .chain([
+            chat_validate_t.as_ref(),
+            control_t.as_ref(),
+            overlay_t.as_ref(),
+        ])
+        .flatten()
+        .map(JoinHandle::abort_handle)
+        .collect();
     loop {
-        let access_token = store.access_token();
+        let access_token = {
+            let mut helix = chat_helix.lock().await;
+            helix.reload_if_changed()?;
+            helix.access_token().to_string()
+        };
         let chat_t = tokio::spawn(read_chat_client_loop(
             irc_url.clone(),
-            String::from(access_token),
-            settings.username.clone(),
+            access_token,
+            chat_login.clone(),
             settings.channel.clone(),
-            settings.speech_address.clone(),
-            settings.operations.clone(),
+            ChatContext {
+                live: live.clone(),
+                reading: reading.clone(),
+                chat_log: chat_log.clone(),
+                outbox: outbox.clone(),
+                greeter: greeter.clone(),
+                control: control.clone(),
+                overlay: overlay.clone(),
+            },
             IRC_TIMEOUT_SECS,
-            settings.translate_command.clone(),
         ));
         let sub_event_t = tokio::spawn(sub_event_client_loop(
             event_url.clone(),
-            String::from(access_token),
-            user_id.clone(),
-            settings.client_id.clone(),
-            settings.speech_address.clone(),
-            settings.operations.clone(),
-            settings.greeting_template.clone(),
+            EventContext {
+                helix: events_helix.clone(),
+                user_id: user_id.clone(),
+                live: live.clone(),
+                follow_storm: follow_storm.clone(),
+                reading: reading.clone(),
+                chat_log: chat_log.clone(),
+                greeter: greeter.clone(),
+                control: control.clone(),
+                overlay: overlay.clone(),
+            },
             EVENT_TIMEOUT_SECS,
         ));
         let chat_abort_handle = chat_t.abort_handle();
         let sub_event_abort_handle = sub_event_t.abort_handle();
         tokio::select! {
             r = chat_t => {
-                match r {
-                    Ok(Ok(_)) => {
-                        warn!("connection closed.");
-                        sub_event_abort_handle.abort();
-                    },
-                    Ok(Err(e)) => {
-                        warn!("error {}: try to reconnect.", e);
-                        refresh_tokens_with_backoff(
-                            &mut store,
-                            &settings.client_id,
-                            &settings.client_secret,
-                        )
-                        .await?;
-                        sub_event_abort_handle.abort();
-                    },
-                    Err(e) => bail!(e)
-                }
+                on_session_end(r, &sub_event_abort_handle, &chat_helix, &control, "irc").await?;
             },
             r = sub_event_t => {
-                match r {
-                    Ok(Ok(_)) => {
-                        warn!("connection closed.");
-                        chat_abort_handle.abort();
-                    },
-                    Ok(Err(e)) => {
-                        warn!("error {}: try to reconnect.", e);
-                        refresh_tokens_with_backoff(
-                            &mut store,
-                            &settings.client_id,
-                            &settings.client_secret,
-                        )
-                        .await?;
-                        chat_abort_handle.abort();
-                    },
-                    Err(e) => bail!(e)
-                }
+                on_session_end(r, &chat_abort_handle, &events_helix, &control, "eventsub").await?;
             },
             _ = crate::profiling::wait_for_shutdown() => {
                 warn!("profiling: startup complete, shutting down");
                 chat_abort_handle.abort();
                 sub_event_abort_handle.abort();
+                for t in &background {
+                    t.abort();
+                }
                 return Ok(());
             },
         };